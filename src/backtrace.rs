@@ -0,0 +1,159 @@
+//! Symbolization of the panic backtraces printed by ESP-IDF and NuttX on Xtensa chips.
+
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::prelude::*;
+
+/// Program counters of the `Backtrace: 0x42008a39:0x3fc9a2d0 0x42009b0f:0x3fc9a2f0 ...` lines
+/// of an ESP-IDF crash dump, or the `Backtrace0: 42009d5c:3fc8ec60 ...` lines of NuttX.
+/// Each frame is a PC:SP pair, a zero PC or ESP-IDF's `|<-CORRUPTED` marker ends the chain.
+pub fn addresses(context: &str) -> Vec<u64> {
+    context
+        .lines()
+        .filter_map(|line| {
+            let (_, frames) = line.split_once("Backtrace")?;
+            frames
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .strip_prefix(':')
+        })
+        .flat_map(|frames| {
+            frames
+                .split_whitespace()
+                .map_while(|frame| {
+                    let (pc, _sp) = frame.split_once(':')?;
+                    u64::from_str_radix(pc.trim_start_matches("0x"), 16).ok()
+                })
+                .take_while(|&pc| pc != 0)
+        })
+        .collect()
+}
+
+/// Symbolize the backtrace found in `context` against `elf`, running the command that
+/// `addr2line` builds for the space separated addresses. It's only called when there is
+/// something to decode, so it may set up the toolchain lazily.
+///
+/// Returns the decoded stack trace to append to the crash output, or `None` if there was
+/// nothing to decode or decoding failed, which is only logged since the crash is what matters.
+pub async fn decode(
+    context: &str,
+    elf: &Path,
+    addr2line: impl AsyncFnOnce(&str) -> Result<Command>,
+) -> Option<String> {
+    let addresses = addresses(context);
+    if addresses.is_empty() {
+        return None;
+    }
+    if !elf.exists() {
+        warn!(
+            "No ELF file at {}, can't decode the backtrace",
+            elf.display()
+        );
+        return None;
+    }
+
+    let addresses: Vec<String> = addresses.iter().map(|pc| format!("{pc:#x}")).collect();
+    info!("Decoding backtrace against {}", elf.display());
+
+    let mut addr2line = match addr2line(&addresses.join(" ")).await {
+        Ok(addr2line) => addr2line,
+        Err(err) => {
+            warn!("Can't run addr2line: {err}");
+            return None;
+        }
+    };
+    let output = match addr2line.output().await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            warn!(
+                "addr2line exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Err(err) => {
+            warn!("Failed to run addr2line: {err}");
+            return None;
+        }
+    };
+
+    let mut decoded = String::from("\nDecoded backtrace:\n");
+    decoded.push_str(&String::from_utf8_lossy(&output.stdout));
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::test_dir;
+
+    const ESP32S3_EVE_CRASH: &str = include_str!("../testdata/transcripts/esp32s3-eve-crash.log");
+
+    #[test]
+    fn esp_idf_backtrace() {
+        assert_eq!(
+            addresses(ESP32S3_EVE_CRASH),
+            vec![0x42008a39, 0x42009b0f, 0x4037a1c6]
+        );
+    }
+
+    #[test]
+    fn esp_idf_corrupted_backtrace() {
+        let context = "Backtrace: 0x42008a39:0x3fc9a2d0 0x42009b0f:0x3fc9a2f0 |<-CORRUPTED\n";
+        assert_eq!(addresses(context), vec![0x42008a39, 0x42009b0f]);
+    }
+
+    #[test]
+    fn nuttx_backtrace() {
+        let context = "\
+xtensa_user_panic: User Exception: EXCCAUSE=001c task: lvgldemo
+sched_dumpstack: backtrace| 2: 0x42009d5c 0x40376a10
+Backtrace0: 42009d5c:3fc8ec60 40376a10:3fc8ec80
+Backtrace1: 4200a1b4:3fc8eca0 00000000:3fc8ecc0 4200c000:3fc8ece0
+";
+        assert_eq!(addresses(context), vec![0x42009d5c, 0x40376a10, 0x4200a1b4]);
+    }
+
+    #[test]
+    fn no_backtrace() {
+        assert!(addresses("Guru Meditation Error: Core  1 panic'ed (LoadProhibited)\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn decode_runs_addr2line_on_the_addresses() {
+        let elf = test_dir("backtrace").join("firmware.elf");
+        std::fs::write(&elf, "").unwrap();
+
+        let decoded = decode(ESP32S3_EVE_CRASH, &elf, async |addresses| {
+            let mut command = Command::new("echo");
+            command.arg(addresses);
+            Ok(command)
+        })
+        .await;
+        assert_eq!(
+            decoded.as_deref(),
+            Some("\nDecoded backtrace:\n0x42008a39 0x42009b0f 0x4037a1c6\n")
+        );
+
+        let missing = elf.with_file_name("missing.elf");
+        assert_eq!(
+            decode(ESP32S3_EVE_CRASH, &missing, async |_| unreachable!()).await,
+            None
+        );
+        // The toolchain is only set up when there are addresses to decode.
+        assert_eq!(
+            decode("Guru Meditation Error\n", &elf, async |_| unreachable!()).await,
+            None
+        );
+        assert_eq!(
+            decode(ESP32S3_EVE_CRASH, &elf, async |_| {
+                Err(Error::ConfigError("no ESP-IDF".to_string()))
+            })
+            .await,
+            None
+        );
+    }
+}
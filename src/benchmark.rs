@@ -0,0 +1,58 @@
+//! Parsing of the LVGL benchmark summary printed at the end of a run.
+
+/// One row of the benchmark summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneResult {
+    pub name: String,
+    /// Average CPU usage in percent
+    pub cpu: u32,
+    /// Average frames per second
+    pub fps: u32,
+    /// Average frame time in milliseconds
+    pub avg_time: u32,
+    /// Average render time in milliseconds
+    pub render_time: u32,
+    /// Average flush time in milliseconds
+    pub flush_time: u32,
+}
+
+/// Extract the per-scene results from the csv summary printed by `lv_demo_benchmark`:
+///
+/// ```text
+/// Name,Avg. CPU,Avg. FPS,Avg. time,render time,flush time
+/// Empty screen,11%,29,1,1,0
+/// ```
+///
+/// Any line that doesn't look like a summary row is ignored, so the whole
+/// output of a run can be passed in.
+pub fn parse_scenes(output: &str) -> Vec<SceneResult> {
+    output.lines().filter_map(parse_scene).collect()
+}
+
+fn parse_scene(line: &str) -> Option<SceneResult> {
+    // Split from the right so scene names containing commas survive.
+    let mut fields = line.trim().rsplitn(6, ',');
+    let flush_time = parse_number(fields.next()?)?;
+    let render_time = parse_number(fields.next()?)?;
+    let avg_time = parse_number(fields.next()?)?;
+    let fps = parse_number(fields.next()?)?;
+    let cpu = parse_number(fields.next()?)?;
+    let name = fields.next()?.trim();
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(SceneResult {
+        name: name.to_string(),
+        cpu,
+        fps,
+        avg_time,
+        render_time,
+        flush_time,
+    })
+}
+
+fn parse_number(field: &str) -> Option<u32> {
+    field.trim().trim_end_matches('%').trim().parse().ok()
+}
@@ -0,0 +1,546 @@
+//! Capture of the benchmark output printed by a board over its serial port.
+
+use std::fmt::Write as _;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::time::{Instant, timeout};
+use tokio_serial::SerialStream;
+use tracing::{info, warn};
+
+use crate::config::{BoardConfigSettings, CaptureConfig, ConsoleStep, PromptConfig};
+use crate::prelude::*;
+use crate::reset::reset;
+
+/// How long to wait for each further line of a crash dump once a failure pattern matched.
+const CRASH_CONTEXT_IDLE: Duration = Duration::from_secs(1);
+
+/// How long the board may stay quiet before a newline is sent to get a prompt out of it.
+const PROMPT_POKE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Timestamped record of every byte read from the port.
+///
+/// Each line read is written as `[+<seconds since start>] <line>`, with invalid UTF-8 and
+/// control characters escaped (`\xNN`, `\r`, `\n`...) so the exact bytes can be recovered.
+pub struct Transcript<W> {
+    writer: W,
+    started: Instant,
+}
+
+impl<W: AsyncWrite + Unpin> Transcript<W> {
+    pub async fn start(mut writer: W) -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        writer
+            .write_all(format!("# started at unix time {:.3}\n", now.as_secs_f64()).as_bytes())
+            .await?;
+
+        Ok(Self {
+            writer,
+            started: Instant::now(),
+        })
+    }
+
+    async fn record(&mut self, bytes: &[u8]) -> Result<()> {
+        let line = format!(
+            "[+{:10.3}] {}\n",
+            self.started.elapsed().as_secs_f64(),
+            escape(bytes)
+        );
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+/// Run a whole session on the application `port`: reset the board, wait for its prompt,
+/// run the console scripts around the benchmark and capture its output.
+///
+/// Everything read is recorded to `transcript_path` and, even when the session fails, kept in
+/// `output` and written to `results_path` to see how far the board got.
+pub async fn session(
+    port: &mut SerialStream,
+    settings: &BoardConfigSettings,
+    results_path: &Path,
+    transcript_path: &Path,
+    output: &mut String,
+) -> Result<()> {
+    let mut transcript = Transcript::start(tokio::fs::File::create(transcript_path).await?).await?;
+    info!(
+        "Recording serial transcript to {}",
+        transcript_path.display()
+    );
+
+    let result = run_session(port, settings, output, &mut transcript).await;
+
+    tokio::fs::write(results_path, &output).await?;
+    result
+}
+
+async fn run_session(
+    port: &mut SerialStream,
+    settings: &BoardConfigSettings,
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<()> {
+    reset(port, &settings.reset).await?;
+
+    // Buffered once for the whole session so the script after the benchmark sees everything.
+    let mut console = BufReader::new(port);
+
+    if let Some(prompt) = &settings.prompt {
+        wait_for_prompt(&mut console, prompt, output, transcript).await?;
+    }
+
+    let before = settings.console.before.as_deref().unwrap_or_default();
+    run_script(&mut console, before, output, transcript).await?;
+
+    capture(&mut console, &settings.capture, output, transcript).await?;
+
+    run_script(&mut console, &settings.console.after, output, transcript).await
+}
+
+/// Read `port` line by line into `output` until a success marker shows up.
+///
+/// `port` is buffered by the caller so nothing printed after the marker is lost for
+/// whatever talks to the board next.
+///
+/// Gives up once the board stays silent for the idle timeout or the run deadline passes,
+/// whichever comes first. `output` holds everything captured so far even when this fails,
+/// so callers can keep it. Bytes that aren't valid UTF-8 are replaced rather than dropped.
+pub async fn capture(
+    mut port: impl AsyncBufRead + Unpin,
+    config: &CaptureConfig,
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<()> {
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let deadline = Instant::now() + Duration::from_secs(config.run_deadline_secs);
+
+    loop {
+        let mut buffer = Vec::new();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let read = timeout(
+            idle_timeout.min(remaining),
+            port.read_until(b'\n', &mut buffer),
+        )
+        .await;
+
+        let n = match read {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) if err.kind() != ErrorKind::TimedOut => return Err(err.into()),
+            // Either our timer or the port's own read timeout fired.
+            _ => {
+                // Keep whatever part of the last line made it in.
+                if !buffer.is_empty() {
+                    transcript.record(&buffer).await?;
+                    output.push_str(&String::from_utf8_lossy(&buffer));
+                }
+                if remaining <= idle_timeout {
+                    warn!("Run deadline of {}s reached", config.run_deadline_secs);
+                } else {
+                    warn!("No output for {}s", config.idle_timeout_secs);
+                }
+                return Err(Error::TimeoutWaitingForBenchmarkToEnd(output.clone()));
+            }
+        };
+
+        if n == 0 {
+            return Err(Error::TimeoutWaitingForBenchmarkToEnd(output.clone()));
+        }
+
+        transcript.record(&buffer).await?;
+        let line = String::from_utf8_lossy(&buffer);
+        output.push_str(&line);
+
+        if let Some(pattern) = config
+            .failure_patterns
+            .iter()
+            .find(|pattern| line.contains(pattern.as_str()))
+        {
+            let context_start = output.len() - line.len();
+            read_crash_context(&mut port, config.crash_context_lines, output, transcript).await?;
+            return Err(Error::DeviceCrashed(
+                pattern.clone(),
+                output[context_start..].to_string(),
+            ));
+        }
+
+        if config
+            .success_markers
+            .iter()
+            .any(|marker| line.contains(marker.as_str()))
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Read `port` into `output` until `prompt` shows up.
+///
+/// The prompt may have been printed before the port was opened, so a newline is sent
+/// whenever the board stays quiet for a second to make the shell print a fresh one.
+pub async fn wait_for_prompt<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    prompt: &PromptConfig,
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<()> {
+    let limit = Duration::from_secs(prompt.timeout_secs);
+    if read_until_text(port, &prompt.text, limit, true, output, transcript).await? {
+        Ok(())
+    } else {
+        Err(Error::TimeoutWaitingForPrompt(prompt.text.clone()))
+    }
+}
+
+/// Run the `send`/`expect` steps of a console script on `port`, keeping what the board
+/// prints in `output`.
+pub async fn run_script<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    steps: &[ConsoleStep],
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<()> {
+    for step in steps {
+        if let Some(send) = &step.send {
+            port.write_all(send.as_bytes()).await?;
+            port.flush().await?;
+        }
+
+        if let Some(expect) = &step.expect {
+            let limit = Duration::from_secs(step.timeout_secs);
+            if !read_until_text(port, expect, limit, false, output, transcript).await? {
+                return Err(Error::ConsoleExpectTimeout(expect.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read `port` into `output` until `text` shows up, returning whether it did within `limit`.
+/// With `poke`, a newline is sent every time the board stays quiet for a second.
+async fn read_until_text<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    text: &str,
+    limit: Duration,
+    poke: bool,
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<bool> {
+    let deadline = Instant::now() + limit;
+    let search_start = output.len();
+    let mut buffer = [0u8; 256];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+
+        match timeout(PROMPT_POKE_INTERVAL.min(remaining), port.read(&mut buffer)).await {
+            Ok(Ok(0)) => return Ok(false),
+            Ok(Ok(n)) => {
+                transcript.record(&buffer[..n]).await?;
+                output.push_str(&String::from_utf8_lossy(&buffer[..n]));
+                if output[search_start..].contains(text) {
+                    return Ok(true);
+                }
+            }
+            Ok(Err(err)) if err.kind() != ErrorKind::TimedOut => return Err(err.into()),
+            _ if poke => {
+                port.write_all(b"\n").await?;
+                port.flush().await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Keep reading the crash dump (registers, backtrace...) that follows a failure pattern,
+/// until `max_lines` were read or the board goes quiet.
+async fn read_crash_context(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    max_lines: usize,
+    output: &mut String,
+    transcript: &mut Transcript<impl AsyncWrite + Unpin>,
+) -> Result<()> {
+    for _ in 0..max_lines {
+        let mut buffer = Vec::new();
+        match timeout(CRASH_CONTEXT_IDLE, reader.read_until(b'\n', &mut buffer)).await {
+            Ok(Ok(n)) if n > 0 => {
+                transcript.record(&buffer).await?;
+                output.push_str(&String::from_utf8_lossy(&buffer));
+            }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push('\t'),
+                '\r' => escaped.push_str("\\r"),
+                '\n' => escaped.push_str("\\n"),
+                c if c.is_control() => {
+                    let mut encoded = [0u8; 4];
+                    for byte in c.encode_utf8(&mut encoded).bytes() {
+                        let _ = write!(escaped, "\\x{byte:02x}");
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            let _ = write!(escaped, "\\x{byte:02x}");
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::parse_scenes;
+    use crate::scratch::test_dir;
+    use crate::serial_sim::Board;
+
+    const ESP32S3_EVE: &str = include_str!("../testdata/transcripts/esp32s3-eve.log");
+    const ESP32S3_EVE_CRASH: &str = include_str!("../testdata/transcripts/esp32s3-eve-crash.log");
+
+    struct Outcome {
+        result: Result<()>,
+        output: String,
+        results_file: String,
+        transcript: String,
+        received: Vec<u8>,
+    }
+
+    /// Run a whole session against `board`, with the board config settings given as TOML.
+    async fn run(name: &str, board: Board, settings: &str) -> Outcome {
+        let settings: BoardConfigSettings = toml::from_str(settings).unwrap();
+        let dir = test_dir(name);
+        let results_path = dir.join("results");
+        let transcript_path = dir.join("results.transcript.log");
+
+        let (mut port, board) = board.spawn();
+        let mut output = String::new();
+        let result = session(
+            &mut port,
+            &settings,
+            &results_path,
+            &transcript_path,
+            &mut output,
+        )
+        .await;
+        drop(port);
+
+        Outcome {
+            result,
+            output,
+            results_file: std::fs::read_to_string(&results_path).unwrap(),
+            transcript: std::fs::read_to_string(&transcript_path).unwrap(),
+            received: board.await.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_run_ends_at_success_marker() {
+        let board = Board::new().replay(ESP32S3_EVE, 10.0);
+        let outcome = run("success", board, "").await;
+
+        outcome.result.unwrap();
+        assert!(outcome.output.ends_with("Benchmark Over\r\n"));
+        assert_eq!(outcome.results_file, outcome.output);
+        assert_eq!(parse_scenes(&outcome.results_file).len(), 3);
+        assert!(outcome.transcript.starts_with("# started at unix time "));
+        assert!(
+            outcome
+                .transcript
+                .contains("] \\x1b[0;32mI (312) main_task: Calling app_main()\\x1b[0m\\r\\n\n")
+        );
+    }
+
+    #[test]
+    fn escaped_transcript_bytes() {
+        assert_eq!(
+            escape(b"\x1b[0m\tok\\\x00\xe9\x13\r\n"),
+            "\\x1b[0m\tok\\\\\\x00\\xe9\\x13\\r\\n"
+        );
+        assert_eq!(escape("é\u{85}".as_bytes()), "é\\xc2\\x85");
+    }
+
+    #[tokio::test]
+    async fn crash_keeps_dump_and_garbage() {
+        let board = Board::new().replay(ESP32S3_EVE_CRASH, 10.0);
+        let outcome = run("crash", board, "").await;
+
+        let Err(Error::DeviceCrashed(pattern, context)) = outcome.result else {
+            panic!("expected a crash, got {:?}", outcome.result);
+        };
+        assert_eq!(pattern, "Guru Meditation Error");
+        assert!(context.starts_with("Guru Meditation Error: Core  1 panic'ed"));
+        assert!(context.contains("Backtrace: 0x42008a39:0x3fc9a2d0"));
+        assert!(context.contains("ELF file SHA256: 4f1a\u{fffd}\u{fffd}2c"));
+        assert!(outcome.results_file.contains("Rebooting..."));
+        assert!(
+            outcome
+                .transcript
+                .contains("] ELF file SHA256: 4f1a\\xff\\xfe2c\\r\\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn crash_context_is_limited() {
+        let board = Board::new().replay(ESP32S3_EVE_CRASH, 10.0);
+        let outcome = run("crash-context", board, "[capture]\ncrash_context_lines = 2").await;
+
+        let Err(Error::DeviceCrashed(_, context)) = outcome.result else {
+            panic!("expected a crash, got {:?}", outcome.result);
+        };
+        assert_eq!(context.lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn markers_split_across_writes_and_invalid_utf8() {
+        let board = Board::new()
+            .print(b"\xe9\x00\x13 boot garbage\r\n")
+            .print("Bench")
+            .delay(100)
+            .print("mark O")
+            .delay(100)
+            .print("ver\r\n")
+            .print("not read\r\n");
+        let settings = "[capture]\nsuccess_markers = [\"Benchmark Over\"]";
+        let outcome = run("split-marker", board, settings).await;
+
+        outcome.result.unwrap();
+        assert!(
+            outcome
+                .output
+                .starts_with("\u{fffd}\0\u{13} boot garbage\r\n")
+        );
+        assert!(outcome.output.ends_with("Benchmark Over\r\n"));
+    }
+
+    #[tokio::test]
+    async fn silent_board_hits_idle_timeout() {
+        let board = Board::new().print("Benchmark Start\r\npartial li");
+        let settings = "[capture]\nidle_timeout_secs = 1";
+        let outcome = run("idle-timeout", board, settings).await;
+
+        let Err(Error::TimeoutWaitingForBenchmarkToEnd(output)) = outcome.result else {
+            panic!("expected a timeout, got {:?}", outcome.result);
+        };
+        assert_eq!(output, "Benchmark Start\r\npartial li");
+        assert_eq!(outcome.results_file, output);
+    }
+
+    #[tokio::test]
+    async fn chatty_board_hits_run_deadline() {
+        let mut board = Board::new();
+        for _ in 0..40 {
+            board = board.print("still rendering\r\n").delay(100);
+        }
+        let settings = "[capture]\nrun_deadline_secs = 1\nidle_timeout_secs = 10";
+        let outcome = run("run-deadline", board, settings).await;
+
+        assert!(matches!(
+            outcome.result,
+            Err(Error::TimeoutWaitingForBenchmarkToEnd(_))
+        ));
+        // Gave up about a second into the 4s of output.
+        let lines = outcome.output.lines().count();
+        assert!((5..20).contains(&lines), "captured {lines} lines");
+    }
+
+    #[tokio::test]
+    async fn unplugged_board_fails() {
+        let board = Board::new()
+            .print("Benchmark Start\r\n")
+            .delay(200)
+            .hang_up();
+        let outcome = run("hang-up", board, "").await;
+
+        assert!(outcome.result.is_err());
+        assert_eq!(outcome.results_file, "Benchmark Start\r\n");
+    }
+
+    #[tokio::test]
+    async fn console_scripts_drive_the_shell() {
+        // The prompt went out before the port was opened, so only a newline brings it back.
+        let board = Board::new()
+            .print("NuttShell (NSH) NuttX-12.8.0\r\n")
+            .expect("\n")
+            .print("\r\nnsh> ")
+            .expect("my_lvgl_app\n")
+            .print("Benchmark Start\r\nEmpty screen,11%,29,1,1,0\r\nBenchmark Over\r\nnsh> ")
+            .expect("free\n")
+            .print("      total       used       free\r\nUmem: 400000 120000 280000\r\nnsh> ");
+        let settings = r#"
+            [prompt]
+            text = "nsh> "
+            timeout_secs = 5
+
+            [[console.before]]
+            send = "my_lvgl_app\n"
+
+            [[console.after]]
+            expect = "nsh> "
+            [[console.after]]
+            send = "free\n"
+            expect = "nsh> "
+        "#;
+        let outcome = run("console", board, settings).await;
+
+        outcome.result.unwrap();
+        let received = String::from_utf8(outcome.received).unwrap();
+        assert!(received.ends_with("\nmy_lvgl_app\nfree\n"));
+        assert!(outcome.results_file.contains("Umem: 400000 120000 280000"));
+        assert_eq!(parse_scenes(&outcome.results_file).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_prompt_times_out() {
+        let board = Board::new().print("rst:0x1 (POWERON)\r\n");
+        let settings = "[prompt]\ntext = \"nsh> \"\ntimeout_secs = 2";
+        let outcome = run("no-prompt", board, settings).await;
+
+        assert!(matches!(
+            outcome.result,
+            Err(Error::TimeoutWaitingForPrompt(prompt)) if prompt == "nsh> "
+        ));
+        // Pokes at the shell every second while waiting.
+        assert!(outcome.received.starts_with(b"\n"));
+        assert_eq!(outcome.results_file, "rst:0x1 (POWERON)\r\n");
+    }
+
+    #[tokio::test]
+    async fn console_expect_times_out() {
+        let board = Board::new().expect("ps\n").print("  PID GROUP PRI\r\n");
+        let settings = r#"
+            [[console.before]]
+            send = "ps\n"
+            expect = "nsh> "
+            timeout_secs = 1
+        "#;
+        let outcome = run("expect-timeout", board, settings).await;
+
+        assert!(matches!(
+            outcome.result,
+            Err(Error::ConsoleExpectTimeout(expect)) if expect == "nsh> "
+        ));
+        assert_eq!(outcome.results_file, "  PID GROUP PRI\r\n");
+    }
+}
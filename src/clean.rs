@@ -0,0 +1,144 @@
+//! Removal of the build products compilers and make leave in source trees.
+
+use std::path::{Path, PathBuf};
+
+use async_recursion::async_recursion;
+use tracing::{debug, info};
+
+use crate::prelude::*;
+
+/// Extensions of objects, dependency files and archives.
+const BUILD_PRODUCT_EXTENSIONS: &[&str] = &["o", "d", "a"];
+
+/// Dependency lists the NuttX apps Makefiles write next to the sources.
+const BUILD_PRODUCT_NAMES: &[&str] = &["Make.dep", ".depend"];
+
+fn is_build_product(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| BUILD_PRODUCT_NAMES.iter().any(|product| name == *product))
+        || path.extension().is_some_and(|ext| {
+            BUILD_PRODUCT_EXTENSIONS
+                .iter()
+                .any(|product| ext == *product)
+        })
+}
+
+/// Build products under `root`. `root` itself may be a symlink, the symlinks inside it
+/// aren't followed so cleaning never reaches outside the tree.
+pub async fn find_build_products(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut products = Vec::new();
+    if tokio::fs::try_exists(root).await? {
+        walk(&tokio::fs::canonicalize(root).await?, &mut products).await?;
+    }
+    Ok(products)
+}
+
+#[async_recursion]
+async fn walk(dir: &Path, products: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            walk(&path, products).await?;
+        } else if file_type.is_file() && is_build_product(&path) {
+            products.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the build products under `root` and return their paths.
+pub async fn remove_build_products(root: &Path) -> Result<Vec<PathBuf>> {
+    let products = find_build_products(root).await?;
+    for product in &products {
+        debug!("Removing {}", product.display());
+        match tokio::fs::remove_file(product).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    if !products.is_empty() {
+        info!(
+            "Removed {} build products from {}",
+            products.len(),
+            root.display()
+        );
+    }
+    Ok(products)
+}
+
+/// Fail if build products are left under `root`.
+pub async fn verify_clean(root: &Path) -> Result<()> {
+    let products = find_build_products(root).await?;
+    match products.first() {
+        None => Ok(()),
+        Some(first) => Err(Error::CleanFailed(format!(
+            "{} build products left under {}, e.g. {}",
+            products.len(),
+            root.display(),
+            first.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::test_dir;
+
+    fn touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    #[tokio::test]
+    async fn removes_build_products_only() {
+        let dir = test_dir("clean");
+        let app = dir.join("apps").join("graphics").join("lvgl");
+        let kept = [
+            "Kconfig",
+            "Makefile",
+            "lvgl/src/core/lv_obj.c",
+            "lvgl/src/core/lv_obj.h",
+            "lvgl/docs/build.md",
+        ];
+        let removed = [
+            "Make.dep",
+            ".depend",
+            "lvgl/src/core/lv_obj.o",
+            "lvgl/src/core/lv_obj.d",
+            "lvgl/src/with space/lv draw.o",
+            "liblvgl.a",
+        ];
+        for file in kept.iter().chain(&removed) {
+            touch(&app.join(file));
+        }
+        // Linked trees are left alone.
+        let outside = dir.join("outside");
+        touch(&outside.join("keep.o"));
+        std::os::unix::fs::symlink(&outside, app.join("linked")).unwrap();
+
+        assert!(verify_clean(&app).await.is_err());
+        let products = remove_build_products(&app).await.unwrap();
+        assert_eq!(products.len(), removed.len());
+        verify_clean(&app).await.unwrap();
+
+        for file in kept {
+            assert!(app.join(file).exists(), "{file} was removed");
+        }
+        assert!(outside.join("keep.o").exists());
+    }
+
+    #[tokio::test]
+    async fn follows_symlinked_root() {
+        let dir = test_dir("clean-symlinked-root");
+        let lvgl = dir.join("lvgl");
+        touch(&lvgl.join("src").join("lv_init.o"));
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&lvgl, &link).unwrap();
+
+        assert_eq!(remove_build_products(&link).await.unwrap().len(), 1);
+        assert!(!lvgl.join("src").join("lv_init.o").exists());
+    }
+}
@@ -1,5 +1,6 @@
 use serde::Deserialize;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 
 use crate::prelude::*;
 
@@ -33,13 +34,604 @@ fn default_commit() -> String {
     "master".to_string()
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct JunitConfig {
+    /// Folder, relative to the workspace, where JUnit XML reports are written
+    /// No reports are written unless this is set
+    pub dir: Option<PathBuf>,
+
+    /// Maximum FPS drop in percent allowed for a scene compared to the
+    /// previous results before it's reported as a regression
+    /// Regressions aren't checked unless this is set
+    pub fps_regression_threshold: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FirmwareSizeConfig {
+    /// Size report, relative to the workspace, to compare new builds against
+    /// Defaults to the report of the previous build
+    pub baseline: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MetricsConfig {
+    /// Folder where OpenMetrics text files are written after each job, e.g. the
+    /// node-exporter textfile collector folder. Relative paths start at the workspace
+    /// No metrics are written unless this is set
+    pub dir: Option<PathBuf>,
+}
+
 #[derive(Debug, Deserialize)]
+pub struct DeviceLocksConfig {
+    /// Folder holding the lock files, shared by every builder on the host
+    /// Defaults to `ejlv_builder-locks` in the system temporary folder
+    pub dir: Option<PathBuf>,
+
+    /// Maximum time in seconds to wait for another job to release a device
+    #[serde(default = "default_lock_wait_timeout_secs")]
+    pub wait_timeout_secs: u64,
+}
+
+impl Default for DeviceLocksConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            wait_timeout_secs: default_lock_wait_timeout_secs(),
+        }
+    }
+}
+
+fn default_lock_wait_timeout_secs() -> u64 {
+    1800
+}
+
+/// An ESP-IDF installation
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdfInstallation {
+    /// Folder of the ESP-IDF checkout, holding `export.sh`
+    pub path: PathBuf,
+
+    /// Version the installation must have, e.g. "5.3.1" or "5.3"
+    /// Checked against `tools/cmake/version.cmake` before using it
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct EspIdfConfig {
+    /// Installations available on the host, selected by name with `esp_idf` in the board configs
+    #[serde(default)]
+    pub installations: HashMap<String, IdfInstallation>,
+}
+
+/// Where the results file of each job is written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultsLayout {
+    /// `results-<board>-<board config>` in the workspace folder
+    Flat,
+    /// `results/<board>/<board config>` in the workspace folder
+    Directory,
+    /// The fixed per-board names used by older versions, e.g. `results-esp32s3`
+    /// Board configs of the same board overwrite each other's results. The default, so
+    /// existing EJ board configs keep finding their results files.
+    #[default]
+    Legacy,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ResultsConfig {
+    #[serde(default)]
+    pub layout: ResultsLayout,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureConfig {
+    /// Output that marks the end of a successful benchmark run
+    /// Defaults to "Benchmark Over"
+    #[serde(default = "default_success_markers")]
+    pub success_markers: Vec<String>,
+
+    /// Output that means the board crashed, matched as plain substrings of each line
+    #[serde(default = "default_failure_patterns")]
+    pub failure_patterns: Vec<String>,
+
+    /// Lines kept after a failure pattern matched, so the crash dump ends up in the error
+    #[serde(default = "default_crash_context_lines")]
+    pub crash_context_lines: usize,
+
+    /// Maximum time in seconds to wait for the benchmark to end, no matter what the board prints
+    #[serde(default = "default_run_deadline_secs")]
+    pub run_deadline_secs: u64,
+
+    /// Maximum time in seconds without any output before giving up
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            success_markers: default_success_markers(),
+            failure_patterns: default_failure_patterns(),
+            crash_context_lines: default_crash_context_lines(),
+            run_deadline_secs: default_run_deadline_secs(),
+            idle_timeout_secs: default_idle_timeout_secs(),
+        }
+    }
+}
+
+fn default_success_markers() -> Vec<String> {
+    vec!["Benchmark Over".to_string()]
+}
+
+fn default_failure_patterns() -> Vec<String> {
+    [
+        "Guru Meditation Error",
+        "assert failed",
+        "Backtrace:",
+        "up_assert",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_crash_context_lines() -> usize {
+    40
+}
+
+fn default_run_deadline_secs() -> u64 {
+    600
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    120
+}
+
+/// How to find the serial port of a board
+/// `path` is used as is, otherwise the USB criteria that are set must all match.
+/// MAC probing is only attempted when `mac` is set and nothing else matched.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SerialPortConfig {
+    /// Fixed device path, preferably a stable `/dev/serial/by-id/...` link
+    pub path: Option<PathBuf>,
+
+    /// USB vendor ID
+    pub vid: Option<u16>,
+
+    /// USB product ID
+    pub pid: Option<u16>,
+
+    /// USB serial number, compared case-insensitively
+    pub serial_number: Option<String>,
+
+    /// MAC address reported by `esptool.py read_mac`
+    pub mac: Option<String>,
+
+    /// Ports probed with esptool when looking for `mac`
+    #[serde(default = "default_probe_ports")]
+    pub probe_ports: Vec<String>,
+}
+
+impl Default for SerialPortConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            vid: None,
+            pid: None,
+            serial_number: None,
+            mac: None,
+            probe_ports: default_probe_ports(),
+        }
+    }
+}
+
+fn default_probe_ports() -> Vec<String> {
+    vec!["/dev/ttyACM0".to_string(), "/dev/ttyACM1".to_string()]
+}
+
+/// One step of a DTR/RTS reset sequence: set the lines that are given, then wait
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResetStep {
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    #[serde(default)]
+    pub delay_ms: u64,
+}
+
+/// Shell prompt to wait for before talking to the board
+#[derive(Debug, Clone, Deserialize)]
+pub struct PromptConfig {
+    /// Text of the prompt, e.g. "nsh> "
+    pub text: String,
+
+    /// Maximum time in seconds to wait for the prompt
+    #[serde(default = "default_prompt_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_prompt_timeout_secs() -> u64 {
+    30
+}
+
+/// One step of a console script: send `send` if given, then wait for `expect` if given
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConsoleStep {
+    /// Text written to the port as is, include the trailing "\n" to run a command
+    pub send: Option<String>,
+
+    /// Text the board must print before the next step
+    pub expect: Option<String>,
+
+    /// Maximum time in seconds to wait for `expect`
+    #[serde(default = "default_expect_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_expect_timeout_secs() -> u64 {
+    10
+}
+
+/// Expect-style scripts run on the application port around the benchmark
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct ConsoleScript {
+    /// Steps run once the prompt showed up, to start the benchmark.
+    /// NuttX configs default to sending the `nuttx.app` command when not set.
+    pub before: Option<Vec<ConsoleStep>>,
+
+    /// Steps run after the benchmark ended successfully, e.g. to collect `free` or `ps`
+    #[serde(default)]
+    pub after: Vec<ConsoleStep>,
+}
+
+/// Retries and verification of flashing
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlashConfig {
+    /// Attempts before giving up, including the first one
+    #[serde(default = "default_flash_attempts")]
+    pub attempts: u32,
+
+    /// Wait before the second attempt in seconds, doubled before every further one
+    #[serde(default = "default_flash_backoff_secs")]
+    pub backoff_secs: u64,
+
+    /// Baud rate of the first attempt, defaults to the one of the flashing tool
+    pub baud: Option<u32>,
+
+    /// Baud rate of the attempts following a failure, e.g. 115200 for flaky USB-UART bridges
+    pub fallback_baud: Option<u32>,
+
+    /// Compare the flash contents with the written images after writing them
+    #[serde(default)]
+    pub verify: bool,
+
+    #[serde(default)]
+    pub tool: FlashTool,
+
+    /// Flash size the native flasher configures, e.g. "16MB", defaults to the one
+    /// of the ESP-IDF build
+    pub flash_size: Option<String>,
+
+    /// DTR/RTS sequence the native flasher resets the board into its bootloader with,
+    /// defaults to the one matching the USB-Serial/JTAG or the usual auto-program circuit
+    pub bootloader_reset: Option<Vec<ResetStep>>,
+}
+
+/// What ESP32 boards are probed and flashed with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlashTool {
+    /// `idf.py` and `esptool.py` from the ESP-IDF installation
+    #[default]
+    Esptool,
+
+    /// The builder talking to the ROM bootloader itself
+    Native,
+}
+
+impl Default for FlashConfig {
+    fn default() -> Self {
+        Self {
+            attempts: default_flash_attempts(),
+            backoff_secs: default_flash_backoff_secs(),
+            baud: None,
+            fallback_baud: None,
+            verify: false,
+            tool: FlashTool::default(),
+            flash_size: None,
+            bootloader_reset: None,
+        }
+    }
+}
+
+fn default_flash_attempts() -> u32 {
+    3
+}
+
+fn default_flash_backoff_secs() -> u64 {
+    2
+}
+
+/// Value of a Kconfig option set from config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum KconfigValue {
+    /// `y`, or `is not set` when false
+    Bool(bool),
+    Integer(i64),
+    /// Written quoted, except for hex values such as "0x1000"
+    String(String),
+}
+
+/// Wrapper generated around LVGL's own Kconfig to hook it into the NuttX apps menu
+#[derive(Debug, Clone, Deserialize)]
+pub struct NuttxKconfig {
+    /// Symbol of the menu enabling LVGL
+    #[serde(default = "default_kconfig_symbol")]
+    pub symbol: String,
+
+    /// Prompt of the menu enabling LVGL
+    #[serde(default = "default_kconfig_prompt")]
+    pub prompt: String,
+
+    /// Kconfig entries appended inside the menu, after LVGL's options
+    #[serde(default = "default_kconfig_extra")]
+    pub extra: String,
+}
+
+impl Default for NuttxKconfig {
+    fn default() -> Self {
+        Self {
+            symbol: default_kconfig_symbol(),
+            prompt: default_kconfig_prompt(),
+            extra: default_kconfig_extra(),
+        }
+    }
+}
+
+fn default_kconfig_symbol() -> String {
+    "GRAPHICS_LVGL".to_string()
+}
+
+fn default_kconfig_prompt() -> String {
+    "Light and Versatile Graphic Library (LVGL)".to_string()
+}
+
+fn default_kconfig_extra() -> String {
+    "config LV_OPTLEVEL\n\tstring \"Customize compilation optimization level\"\n\tdefault \"\"\n"
+        .to_string()
+}
+
+/// How to build and run LVGL on a NuttX board
+#[derive(Debug, Clone, Deserialize)]
+pub struct NuttxConfig {
+    /// `board:config` passed to `tools/configure.sh`, e.g. "esp32s3-lcd-ev:lvgl"
+    pub target: String,
+
+    /// Folder of the workspace holding the `nuttx` and `apps` checkouts
+    #[serde(default = "default_nuttx_project")]
+    pub project: PathBuf,
+
+    /// Folder of the LVGL app inside `apps`, holding LVGL itself in `lvgl`
+    #[serde(default = "default_nuttx_lvgl_app_dir")]
+    pub lvgl_app_dir: PathBuf,
+
+    /// NSH command starting the benchmark
+    #[serde(default = "default_nuttx_app")]
+    pub app: String,
+
+    /// Options set in the configured `.config`, e.g. `LV_USE_DEMO_BENCHMARK = true`
+    #[serde(default)]
+    pub defconfig: BTreeMap<String, KconfigValue>,
+
+    #[serde(default)]
+    pub kconfig: NuttxKconfig,
+
+    /// Environment of `make`, `{project}` is replaced by the absolute project folder
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Command flashing boards without a dedicated flasher, `{bin}`, `{elf}` and `{port}`
+    /// are replaced by the firmware files and the flash port
+    #[serde(default)]
+    pub flash: Vec<String>,
+}
+
+fn default_nuttx_project() -> PathBuf {
+    PathBuf::from("lv_nuttx")
+}
+
+fn default_nuttx_lvgl_app_dir() -> PathBuf {
+    PathBuf::from("graphics/lvgl")
+}
+
+fn default_nuttx_app() -> String {
+    "my_lvgl_app".to_string()
+}
+
+/// How to flash the STM32 port
+#[derive(Debug, Clone, Deserialize)]
+pub struct Stm32Config {
+    /// Command flashing the build, `{elf}`, `{hex}` and `{bin}` are replaced by the firmware files
+    #[serde(default = "default_stm32_flash")]
+    pub flash: Vec<String>,
+}
+
+impl Default for Stm32Config {
+    fn default() -> Self {
+        Self {
+            flash: default_stm32_flash(),
+        }
+    }
+}
+
+fn default_stm32_flash() -> Vec<String> {
+    [
+        "STM32_Programmer_CLI",
+        "-c",
+        "port=SWD",
+        "mode=UR",
+        "-w",
+        "{elf}",
+        "-v",
+        "-rst",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Linux board the benchmark runs on over SSH
+#[derive(Debug, Clone, Deserialize)]
+pub struct SshConfig {
+    pub host: String,
+
+    #[serde(default = "default_ssh_user")]
+    pub user: String,
+
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+
+    /// Private key to log in with, instead of the SSH agent and default keys
+    pub identity_file: Option<PathBuf>,
+
+    /// Folder the binary is copied to and run from, relative to the remote home folder
+    #[serde(default = "default_ssh_remote_dir")]
+    pub remote_dir: String,
+
+    /// Maximum time in seconds to connect, for the reachability check and every command
+    #[serde(default = "default_ssh_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+
+    #[serde(default)]
+    pub when_unreachable: UnreachableAction,
+}
+
+/// The lab's board, for board configs without an `ssh` section
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            host: "192.168.1.172".to_string(),
+            user: default_ssh_user(),
+            port: default_ssh_port(),
+            identity_file: None,
+            remote_dir: default_ssh_remote_dir(),
+            connect_timeout_secs: default_ssh_connect_timeout_secs(),
+            when_unreachable: UnreachableAction::default(),
+        }
+    }
+}
+
+fn default_ssh_user() -> String {
+    "root".to_string()
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn default_ssh_remote_dir() -> String {
+    ".".to_string()
+}
+
+fn default_ssh_connect_timeout_secs() -> u64 {
+    5
+}
+
+/// What a run does when its board doesn't answer over SSH
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreachableAction {
+    /// Write "Skip" as the results and succeed, for boards that are offline at times
+    #[default]
+    Skip,
+    /// Fail the job with `DeviceNotFound`
+    Fail,
+}
+
+/// Settings of a single board config
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct BoardConfigSettings {
+    #[serde(default)]
+    pub capture: CaptureConfig,
+
+    /// Port used to flash the board
+    pub flash_port: Option<SerialPortConfig>,
+
+    /// Port the application prints to, when it isn't the flashing port
+    pub app_port: Option<SerialPortConfig>,
+
+    #[serde(default)]
+    pub flash: FlashConfig,
+
+    /// DTR/RTS sequence applied to the application port before capturing
+    #[serde(default)]
+    pub reset: Vec<ResetStep>,
+
+    /// Prompt to wait for on the application port before capturing
+    pub prompt: Option<PromptConfig>,
+
+    #[serde(default)]
+    pub console: ConsoleScript,
+
+    /// Name of the `esp_idf.installations` entry used by ESP-IDF based board configs
+    pub esp_idf: Option<String>,
+
+    /// LVGL options set for the build, e.g. `LV_USE_DRAW_SW_ASM = 1`, translated to `lv_conf.h`
+    /// defaults, `sdkconfig` or `.config` entries depending on the backend
+    #[serde(default)]
+    pub lv_conf: BTreeMap<String, KconfigValue>,
+
+    /// NuttX build and run settings, makes any board a NuttX board
+    pub nuttx: Option<NuttxConfig>,
+
+    #[serde(default)]
+    pub stm32: Stm32Config,
+
+    /// SSH target of Linux boards
+    pub ssh: Option<SshConfig>,
+
+    /// `addr2line` used to decode crash backtraces, defaults to the one of the board's toolchain
+    pub addr2line: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct EjLvBuilderConfig {
     #[serde(default)]
     pub build_files: BuildFilesConfig,
+
+    #[serde(default)]
+    pub junit: JunitConfig,
+
+    #[serde(default)]
+    pub results: ResultsConfig,
+
+    #[serde(default)]
+    pub firmware_size: FirmwareSizeConfig,
+
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
+    #[serde(default)]
+    pub device_locks: DeviceLocksConfig,
+
+    #[serde(default)]
+    pub esp_idf: EspIdfConfig,
+
+    /// Settings per board config, keyed by board name and then board config name:
+    /// `[boards.esp32s3.eve.capture]`
+    #[serde(default)]
+    pub boards: HashMap<String, HashMap<String, BoardConfigSettings>>,
 }
 
 impl EjLvBuilderConfig {
+    /// Settings of `board_config_name` of `board_name`, or the defaults when not configured.
+    pub fn board_config(&self, board_name: &str, board_config_name: &str) -> BoardConfigSettings {
+        self.boards
+            .get(board_name)
+            .and_then(|configs| configs.get(board_config_name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
     /// Read config from `ejlv_builder_config.toml` in the workspace folder.
     /// If the file doesn't exist, a default config is returned so existing
     /// workspaces that don't have the file keep working as before.
@@ -47,9 +639,7 @@ impl EjLvBuilderConfig {
         let path = workspace.join("ejlv_builder_config.toml");
 
         if !path.exists() {
-            return Ok(Self {
-                build_files: BuildFilesConfig::default(),
-            });
+            return Ok(Self::default());
         }
 
         let contents = tokio::fs::read_to_string(&path).await?;
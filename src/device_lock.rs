@@ -0,0 +1,184 @@
+//! Advisory per-device locks so concurrent jobs never flash or capture the same board.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::time::{Instant, sleep};
+use tracing::info;
+
+use crate::job::Job;
+use crate::prelude::*;
+use crate::slug;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Exclusive lock on a physical device, released when dropped.
+/// The lock is held by the open file so the OS also releases it if the process dies.
+pub struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// Lock `device`, waiting up to the configured timeout for other jobs to release it.
+    ///
+    /// Run jobs take it before touching the board and keep it until the capture is over,
+    /// so no other job probes, flashes or resets the board meanwhile.
+    pub async fn acquire(job: &Job, device: &str) -> Result<Self> {
+        Self::lock(job, device, true)
+            .await
+            .map(|lock| lock.expect("waiting for the lock returns it or an error"))
+    }
+
+    /// Lock the serial port at `path` for as long as the job uses it, so other jobs
+    /// don't probe it meanwhile.
+    pub async fn acquire_port(job: &Job, path: &str) -> Result<Self> {
+        Self::acquire(job, &port_device(path)).await
+    }
+
+    /// Lock the serial port at `path` unless another job uses it right now.
+    pub async fn try_acquire_port(job: &Job, path: &str) -> Result<Option<Self>> {
+        Self::lock(job, &port_device(path), false).await
+    }
+
+    async fn lock(job: &Job, device: &str, wait: bool) -> Result<Option<Self>> {
+        let config = &job.config.device_locks;
+        let dir = config
+            .dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("ejlv_builder-locks"));
+        let owner = format!(
+            "pid {} ({} {})",
+            std::process::id(),
+            job.sdk.board_name(),
+            job.sdk.board_config_name()
+        );
+        let wait_timeout = wait.then(|| Duration::from_secs(config.wait_timeout_secs));
+        Self::lock_in(&dir, device, wait_timeout, &owner).await
+    }
+
+    /// Lock `device` with a lock file in `dir`, waiting up to `wait_timeout` when given,
+    /// and record `owner` in it.
+    async fn lock_in(
+        dir: &Path,
+        device: &str,
+        wait_timeout: Option<Duration>,
+        owner: &str,
+    ) -> Result<Option<Self>> {
+        tokio::fs::create_dir_all(dir).await?;
+
+        let path = dir.join(format!("{}.lock", slug(device)));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        let deadline = wait_timeout.map(|timeout| Instant::now() + timeout);
+        let mut waiting = false;
+        loop {
+            match (file.try_lock(), deadline) {
+                (Ok(()), _) => break,
+                (Err(TryLockError::WouldBlock), None) => return Ok(None),
+                (Err(TryLockError::WouldBlock), Some(deadline)) => {
+                    let holder = holder(&path);
+                    if Instant::now() >= deadline {
+                        return Err(Error::DeviceBusy(device.to_string(), holder));
+                    }
+                    if !waiting {
+                        info!("Waiting for {device}, locked by {holder}");
+                        waiting = true;
+                    }
+                    sleep(POLL_INTERVAL).await;
+                }
+                (Err(TryLockError::Error(err)), _) => return Err(err.into()),
+            }
+        }
+
+        // Let whoever waits on the lock know who holds it.
+        file.set_len(0)?;
+        writeln!(file, "{owner}")?;
+
+        info!("Locked {device}");
+        Ok(Some(Self { _file: file }))
+    }
+}
+
+/// Lock key of the serial port at `path`. The same port is reached through `/dev/serial/by-id`
+/// links and through its `/dev/ttyACM*` name, so the link is resolved first.
+fn port_device(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(path) => format!("port {}", path.display()),
+        Err(_) => format!("port {path}"),
+    }
+}
+
+fn holder(path: &Path) -> String {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|holder| holder.trim().to_string())
+        .filter(|holder| !holder.is_empty())
+        .unwrap_or_else(|| "another job".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::test_dir;
+
+    #[test]
+    fn port_links_share_a_lock() {
+        let dir = test_dir("device-lock-links");
+        let tty = dir.join("ttyACM0");
+        std::fs::write(&tty, "").unwrap();
+        let link = dir.join("usb-Espressif_USB_JTAG_serial_debug_unit_F4:12:FA:00:00:01-if00");
+        std::os::unix::fs::symlink(&tty, &link).unwrap();
+
+        let device = port_device(&tty.to_string_lossy());
+        assert_eq!(port_device(&link.to_string_lossy()), device);
+        assert_eq!(
+            device,
+            format!("port {}", tty.canonicalize().unwrap().display())
+        );
+        assert_eq!(port_device("/dev/ttyEJLV404"), "port /dev/ttyEJLV404");
+    }
+
+    #[tokio::test]
+    async fn second_lock_on_a_port_is_refused() {
+        let dir = test_dir("device-lock-busy");
+        let device = port_device("/dev/ttyACM0");
+
+        let held = DeviceLock::lock_in(&dir, &device, None, "pid 1 (esp32s3 eve)")
+            .await
+            .unwrap();
+        assert!(held.is_some());
+
+        // Probing skips the port, waiting for it gives up once the timeout passed.
+        let probe = DeviceLock::lock_in(&dir, &device, None, "pid 2 (esp32s3 nuttx)")
+            .await
+            .unwrap();
+        assert!(probe.is_none());
+        match DeviceLock::lock_in(&dir, &device, Some(Duration::ZERO), "pid 2 (esp32s3 nuttx)")
+            .await
+        {
+            Err(Error::DeviceBusy(busy, holder)) => {
+                assert_eq!(busy, device);
+                assert_eq!(holder, "pid 1 (esp32s3 eve)");
+            }
+            other => panic!(
+                "expected DeviceBusy, got {:?}",
+                other.map(|lock| lock.is_some())
+            ),
+        }
+
+        drop(held);
+        assert!(
+            DeviceLock::lock_in(&dir, &device, None, "pid 2 (esp32s3 nuttx)")
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! GCC/Clang diagnostics extracted from the build output.
+
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::job::Job;
+use crate::lvgl_folder;
+use crate::prelude::*;
+
+/// Extension of the diagnostics summary written next to the results file.
+pub const SUMMARY_EXTENSION: &str = "diagnostics.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: u32,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Make a relative `file` absolute, taking it relative to `dir`, the folder the compiler ran in.
+    pub fn resolve(mut self, dir: &Path) -> Self {
+        if Path::new(&self.file).is_relative() {
+            self.file = normalize(&dir.join(&self.file).to_string_lossy());
+        }
+        self
+    }
+
+    /// Whether the diagnostic points into the LVGL sources, given the canonical `lvgl_folder`
+    pub fn is_lvgl(&self, lvgl_folder: &Path) -> bool {
+        let file = Path::new(&self.file);
+        std::fs::canonicalize(file)
+            .unwrap_or_else(|_| file.to_path_buf())
+            .starts_with(lvgl_folder)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Summary<'a> {
+    errors: usize,
+    warnings: usize,
+    lvgl_errors: usize,
+    lvgl_warnings: usize,
+    lvgl: Vec<&'a Diagnostic>,
+}
+
+/// Parse a compiler output line such as
+/// `../lvgl/src/core/lv_obj.c:42:5: warning: unused variable 'x' [-Wunused-variable]`.
+/// Notes are ignored since they only add context to a previous diagnostic.
+pub fn parse_line(line: &str) -> Option<Diagnostic> {
+    let line = strip_ansi(line);
+    let line = line.trim_end();
+
+    const SEVERITIES: &[(&str, Severity)] = &[
+        (": fatal error: ", Severity::Error),
+        (": error: ", Severity::Error),
+        (": warning: ", Severity::Warning),
+    ];
+
+    let (location, message, severity) = SEVERITIES.iter().find_map(|(marker, severity)| {
+        line.split_once(marker)
+            .map(|(location, message)| (location, message, *severity))
+    })?;
+
+    let (file, line, column) = parse_location(location)?;
+    let file = file.trim();
+    if file.is_empty() {
+        return None;
+    }
+
+    Some(Diagnostic {
+        file: normalize(file),
+        line,
+        column,
+        severity,
+        message: message.trim().to_string(),
+    })
+}
+
+/// Split `file:line:column` or `file:line`.
+fn parse_location(location: &str) -> Option<(&str, u32, Option<u32>)> {
+    let (rest, last) = location.rsplit_once(':')?;
+    let last: u32 = last.parse().ok()?;
+
+    let with_column = rest
+        .rsplit_once(':')
+        .and_then(|(file, line)| Some((file, line.parse().ok()?, Some(last))));
+
+    Some(with_column.unwrap_or((rest, last, None)))
+}
+
+/// Lexically clean up `path` so the same file reached through different relative paths dedupes.
+fn normalize(path: &str) -> String {
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized.to_string_lossy().into_owned()
+}
+
+/// Remove the terminal color codes compilers emit with `-fdiagnostics-color=always`.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the CSI sequence up to and including its final byte.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Write the diagnostics summary of the job's build next to the results.
+pub async fn write_summary(job: &Job) -> Result<()> {
+    let diagnostics = job.diagnostics();
+    let lvgl_folder = lvgl_folder(&job.sdk.config_path());
+    let lvgl_folder =
+        std::fs::canonicalize(&lvgl_folder).or_else(|_| std::path::absolute(&lvgl_folder))?;
+    let count = |severity: Severity, lvgl_only: bool| {
+        diagnostics
+            .iter()
+            .filter(|d| d.severity == severity && (!lvgl_only || d.is_lvgl(&lvgl_folder)))
+            .count()
+    };
+
+    let summary = Summary {
+        errors: count(Severity::Error, false),
+        warnings: count(Severity::Warning, false),
+        lvgl_errors: count(Severity::Error, true),
+        lvgl_warnings: count(Severity::Warning, true),
+        lvgl: diagnostics
+            .iter()
+            .filter(|d| d.is_lvgl(&lvgl_folder))
+            .collect(),
+    };
+
+    info!(
+        "Build diagnostics: {} errors, {} warnings ({} errors, {} warnings in LVGL)",
+        summary.errors, summary.warnings, summary.lvgl_errors, summary.lvgl_warnings
+    );
+    for diagnostic in &summary.lvgl {
+        if diagnostic.severity == Severity::Error {
+            warn!(
+                "{}:{}: {}",
+                diagnostic.file, diagnostic.line, diagnostic.message
+            );
+        }
+    }
+
+    let contents = toml::to_string(&summary)?;
+    tokio::fs::write(job.results_artifact_path(SUMMARY_EXTENSION), contents).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gcc_warning() {
+        let diagnostic = parse_line(
+            "../lvgl/src/core/lv_obj.c:42:5: warning: unused variable 'x' [-Wunused-variable]\n",
+        )
+        .unwrap();
+        assert_eq!(diagnostic.file, "../lvgl/src/core/lv_obj.c");
+        assert_eq!(diagnostic.line, 42);
+        assert_eq!(diagnostic.column, Some(5));
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(
+            diagnostic.message,
+            "unused variable 'x' [-Wunused-variable]"
+        );
+    }
+
+    #[test]
+    fn clang_colored_error() {
+        let diagnostic = parse_line(
+            "\x1b[1m/work/lvgl/src/misc/lv_area.c:7:10: \x1b[0m\x1b[0;1;31merror: \x1b[0m\x1b[1muse of undeclared identifier 'y'\x1b[0m",
+        )
+        .unwrap();
+        assert_eq!(diagnostic.file, "/work/lvgl/src/misc/lv_area.c");
+        assert_eq!(diagnostic.line, 7);
+        assert_eq!(diagnostic.column, Some(10));
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.message, "use of undeclared identifier 'y'");
+    }
+
+    #[test]
+    fn fatal_error_without_column() {
+        let diagnostic =
+            parse_line("main/main.c:3: fatal error: lvgl.h: No such file or directory").unwrap();
+        assert_eq!(diagnostic.file, "main/main.c");
+        assert_eq!(diagnostic.line, 3);
+        assert_eq!(diagnostic.column, None);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.message, "lvgl.h: No such file or directory");
+    }
+
+    #[test]
+    fn ignored_lines() {
+        assert!(parse_line("src/core/lv_obj.c:40:12: note: 'x' declared here").is_none());
+        assert!(parse_line("make[2]: *** [Makefile:12: build/lv_obj.o] Error 1").is_none());
+        assert!(parse_line("[ 42%] Building C object lvgl/src/core/lv_obj.c.obj").is_none());
+    }
+
+    #[test]
+    fn lvgl_files() {
+        let lvgl_folder = Path::new("/work/ejlv/lvgl");
+        let diagnostic = |file: &str| {
+            parse_line(&format!("{file}:1:1: warning: w"))
+                .unwrap()
+                .resolve(Path::new("/work/ejlv/boards/eve/build"))
+        };
+
+        assert!(diagnostic("../../../lvgl/src/core/lv_obj.c").is_lvgl(lvgl_folder));
+        assert!(diagnostic("/work/ejlv/lvgl/src/core/lv_obj.c").is_lvgl(lvgl_folder));
+        assert!(!diagnostic("../main/lvgl/lv_port.c").is_lvgl(lvgl_folder));
+        assert!(!diagnostic("/work/ejlv/lvgl_port/lv_port.c").is_lvgl(lvgl_folder));
+    }
+}
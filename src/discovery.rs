@@ -0,0 +1,164 @@
+//! Serial port discovery from USB metadata.
+
+use tokio_serial::{SerialPortInfo, SerialPortType, UsbPortInfo};
+use tracing::{info, warn};
+
+use crate::config::SerialPortConfig;
+use crate::prelude::*;
+
+/// Find the port described by `config` through its device path or USB metadata.
+/// Returns `None` when nothing matches or `config` doesn't describe the port that way.
+pub fn find_port(config: &SerialPortConfig) -> Result<Option<String>> {
+    if let Some(path) = &config.path {
+        return Ok(path.exists().then(|| path.to_string_lossy().into_owned()));
+    }
+
+    if config.vid.is_none() && config.pid.is_none() && config.serial_number.is_none() {
+        return Ok(None);
+    }
+
+    let mut matches: Vec<String> = tokio_serial::available_ports()?
+        .into_iter()
+        .filter(|port| usb_info(port).is_some_and(|info| matches_usb(config, &info)))
+        .map(|port| port.port_name)
+        .collect();
+    matches.sort();
+
+    if matches.len() > 1 {
+        warn!(
+            "{} serial ports match {}, using {}",
+            matches.len(),
+            describe(config),
+            matches[0]
+        );
+    }
+
+    let port = matches.into_iter().next();
+    if let Some(port) = &port {
+        info!("Found {} at {}", describe(config), port);
+    }
+    Ok(port)
+}
+
+/// Human readable description of the port `config` is looking for.
+pub fn describe(config: &SerialPortConfig) -> String {
+    let mut criteria = Vec::new();
+    if let Some(path) = &config.path {
+        criteria.push(format!("path={}", path.display()));
+    }
+    if let Some(vid) = config.vid {
+        criteria.push(format!("vid={vid:04x}"));
+    }
+    if let Some(pid) = config.pid {
+        criteria.push(format!("pid={pid:04x}"));
+    }
+    if let Some(serial_number) = &config.serial_number {
+        criteria.push(format!("serial_number={serial_number}"));
+    }
+    if let Some(mac) = &config.mac {
+        criteria.push(format!("mac={mac}"));
+    }
+    format!("serial port ({})", criteria.join(", "))
+}
+
+/// Stable identifier of the physical device `config` points at, used to lock it.
+/// A vid/pid-only selector identifies a board model, so boards of the same model lock each
+/// other. A selector without any criteria is refused rather than sharing one key with every
+/// other unconfigured board.
+pub fn device_id(config: &SerialPortConfig) -> Result<String> {
+    if let Some(serial_number) = &config.serial_number {
+        return Ok(serial_number.to_lowercase());
+    }
+    if let Some(mac) = &config.mac {
+        return Ok(mac.to_lowercase());
+    }
+    if let Some(path) = &config.path {
+        return Ok(path.to_string_lossy().into_owned());
+    }
+    if config.vid.is_none() && config.pid.is_none() {
+        return Err(Error::ConfigError(
+            "A serial port needs a path, vid, pid, serial_number or mac".to_string(),
+        ));
+    }
+    let id = |id: Option<u16>| id.map_or("*".to_string(), |id| format!("{id:04x}"));
+    Ok(format!("{}:{}", id(config.vid), id(config.pid)))
+}
+
+fn usb_info(port: &SerialPortInfo) -> Option<UsbPortInfo> {
+    match &port.port_type {
+        SerialPortType::UsbPort(info) => Some(info.clone()),
+        _ => None,
+    }
+}
+
+fn matches_usb(config: &SerialPortConfig, info: &UsbPortInfo) -> bool {
+    config.vid.is_none_or(|vid| vid == info.vid)
+        && config.pid.is_none_or(|pid| pid == info.pid)
+        && config.serial_number.as_ref().is_none_or(|expected| {
+            info.serial_number
+                .as_ref()
+                .is_some_and(|serial| serial.eq_ignore_ascii_case(expected))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn esp32s3_usb(serial_number: &str) -> UsbPortInfo {
+        UsbPortInfo {
+            vid: 0x303a,
+            pid: 0x1001,
+            serial_number: Some(serial_number.to_string()),
+            manufacturer: Some("Espressif".to_string()),
+            product: Some("USB JTAG/serial debug unit".to_string()),
+        }
+    }
+
+    fn selector(toml: &str) -> SerialPortConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn serial_number_matches_case_insensitively() {
+        let config = selector(r#"serial_number = "f4:12:fa:00:00:01""#);
+        assert!(matches_usb(&config, &esp32s3_usb("F4:12:FA:00:00:01")));
+        assert!(!matches_usb(&config, &esp32s3_usb("F4:12:FA:00:00:02")));
+
+        let mut no_serial = esp32s3_usb("");
+        no_serial.serial_number = None;
+        assert!(!matches_usb(&config, &no_serial));
+    }
+
+    #[test]
+    fn vid_pid_selector() {
+        let config = selector("vid = 0x303a\npid = 0x1001");
+        assert!(matches_usb(&config, &esp32s3_usb("F4:12:FA:00:00:01")));
+        assert!(matches_usb(&config, &esp32s3_usb("F4:12:FA:00:00:02")));
+        assert!(!matches_usb(
+            &config,
+            &UsbPortInfo {
+                pid: 0x3754,
+                ..esp32s3_usb("F4:12:FA:00:00:01")
+            }
+        ));
+        assert_eq!(device_id(&config).unwrap(), "303a:1001");
+        assert_eq!(device_id(&selector("vid = 0x0483")).unwrap(), "0483:*");
+    }
+
+    #[test]
+    fn mac_and_serial_number_lock_the_same_board() {
+        // The USB-Serial/JTAG of the ESP32-S3 reports the MAC as its serial number.
+        let by_mac = selector(r#"mac = "F4:12:FA:00:00:01""#);
+        let by_serial = selector(r#"serial_number = "f4:12:fa:00:00:01""#);
+        assert_eq!(device_id(&by_mac).unwrap(), device_id(&by_serial).unwrap());
+    }
+
+    #[test]
+    fn selector_without_criteria_is_refused() {
+        assert!(matches!(
+            device_id(&SerialPortConfig::default()),
+            Err(Error::ConfigError(_))
+        ));
+    }
+}
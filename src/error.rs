@@ -1,6 +1,7 @@
 //! Error types for ejlv_builder.
 
 #[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// I/O operation failed.
     #[error(transparent)]
@@ -10,6 +11,10 @@ pub enum Error {
     #[error(transparent)]
     BuilderSDK(#[from] ej_builder_sdk::error::Error),
 
+    /// Writing a report as TOML failed
+    #[error(transparent)]
+    TomlSerialize(#[from] toml::ser::Error),
+
     /// Serial Port
     #[error(transparent)]
     SerialPort(#[from] tokio_serial::Error),
@@ -18,14 +23,50 @@ pub enum Error {
     #[error("Timeout Waiting For Benchmark To End - Output: {0}")]
     TimeoutWaitingForBenchmarkToEnd(String),
 
+    /// The board never showed its shell prompt
+    #[error("Timeout waiting for prompt \"{0}\"")]
+    TimeoutWaitingForPrompt(String),
+
+    /// An `expect` step of a console script timed out
+    #[error("Timeout waiting for \"{0}\" in console script")]
+    ConsoleExpectTimeout(String),
+
+    /// A failure pattern showed up in the device output
+    #[error("Device crashed, matched \"{0}\":\n{1}")]
+    DeviceCrashed(String, String),
+
     /// Device not found
     #[error("A device was not found: {0}")]
     DeviceNotFound(String),
 
+    /// Another job kept the device locked for longer than the wait timeout
+    #[error("Device {0} is busy, locked by {1}")]
+    DeviceBusy(String, String),
+
     /// A git command failed
     #[error("Git command Failed: {0}")]
     GitError(String),
 
+    /// A build or run command exited unsuccessfully
+    #[error("Command failed: {0}")]
+    CommandFailed(String),
+
+    /// Build products were left in a source tree after cleaning it
+    #[error("Clean failed: {0}")]
+    CleanFailed(String),
+
+    /// The ESP ROM bootloader didn't answer or rejected a command
+    #[error("ESP ROM loader: {0}")]
+    EspLoader(String),
+
+    /// Every flashing attempt failed
+    #[error("Flashing failed: {0}")]
+    FlashFailed(String),
+
+    /// The firmware size report couldn't be produced
+    #[error("Firmware size report failed: {0}")]
+    FirmwareSize(String),
+
     /// Something went wrong parsing the config
     #[error("Error parsing config: {0}")]
     ConfigError(String),
@@ -1,313 +1,633 @@
-use std::path::PathBuf;
-use std::process::ExitStatus;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use crate::backtrace;
+use crate::capture::session;
+use crate::config::{FlashTool, NuttxConfig, NuttxKconfig, ResetStep, SerialPortConfig};
+use crate::device_lock::DeviceLock;
+use crate::diagnostics::Severity;
+use crate::discovery;
+use crate::esp_idf::{IdfEnv, LazyIdfEnv};
+use crate::esp_loader::{self, EspLoader};
+use crate::firmware_size;
+use crate::flash;
+use crate::job::Job;
+use crate::kconfig;
+use crate::nuttx;
 use crate::prelude::*;
+use crate::source_manifest::{self, SourceManifest};
 use ej_builder_sdk::BuilderSdk;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use serde::Deserialize;
 use tokio::process::Command;
-use tokio::time::sleep;
 use tokio_serial::SerialPortBuilderExt;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
-use crate::{board_folder, results_path};
+use crate::{board_folder, lvgl_folder};
 
-fn idf_version(sdk: &BuilderSdk) -> &'static str {
-    if sdk.board_config_name() == "eve" {
-        "5.3.1"
-    } else {
-        "5.2.5"
-    }
-}
 fn project_path(sdk: &BuilderSdk) -> PathBuf {
     match sdk.board_config_name() {
         "eve" => board_folder(&sdk.config_path(), "eve"),
-        "nuttx" => board_folder(&sdk.config_path(), "lv_nuttx"),
         _ => board_folder(&sdk.config_path(), sdk.board_name()),
     }
 }
-async fn flashing_serial_port(sdk: &BuilderSdk) -> Result<&'static str> {
-    let board_config_name = sdk.board_config_name();
-
-    let mac = if board_config_name == "eve" {
+/// Port settings used when the board config doesn't configure any.
+fn default_flash_port(sdk: &BuilderSdk) -> SerialPortConfig {
+    let mac = if sdk.board_config_name() == "eve" {
         "34:85:18:6c:f6:dc"
     } else {
         "30:30:f9:5a:88:00"
     };
 
-    let ports = ["/dev/ttyACM0", "/dev/ttyACM1"];
-
-    for port in ports {
-        let idf_version = idf_version(sdk);
-        let result = Command::new("bash")
-            .arg("-c")
-            .arg(&format!(
-                ". /home/lvgl/esp/esp-idf{}/export.sh && esptool.py --port {} read_mac",
-                idf_version, port
-            ))
-            .output()
-            .await?;
+    SerialPortConfig {
+        mac: Some(mac.to_string()),
+        ..Default::default()
+    }
+}
+
+fn default_app_port(sdk: &BuilderSdk) -> Option<SerialPortConfig> {
+    (sdk.board_config_name() == "nuttx").then(|| SerialPortConfig {
+        path: Some(PathBuf::from("/dev/ttyUSB0")),
+        ..Default::default()
+    })
+}
+
+/// Serial port of a board, locked for as long as the job uses it.
+struct LockedPort {
+    path: String,
+    /// `None` when the job already holds the port under another role, e.g. flashing.
+    _lock: Option<DeviceLock>,
+}
+
+/// Find the port described by `config` and lock it. `held` is a port the job already locked.
+///
+/// MAC probing resets the boards it talks to, so it only probes ports no other job uses:
+/// a busy port can't be this job's board, whose lock the job holds.
+async fn find_serial_port(
+    job: &Job,
+    idf: &mut LazyIdfEnv<'_>,
+    config: &SerialPortConfig,
+    held: Option<&str>,
+) -> Result<LockedPort> {
+    if let Some(port) = discovery::find_port(config)? {
+        let lock = match held {
+            Some(held) if held == port => None,
+            _ => Some(DeviceLock::acquire_port(job, &port).await?),
+        };
+        return Ok(LockedPort {
+            path: port,
+            _lock: lock,
+        });
+    }
 
-        if String::from_utf8_lossy(&result.stdout).contains(mac) {
-            return Ok(port);
+    if let Some(mac) = &config.mac {
+        for port in &config.probe_ports {
+            let lock = if held == Some(port.as_str()) {
+                None
+            } else {
+                match DeviceLock::try_acquire_port(job, port).await? {
+                    Some(lock) => Some(lock),
+                    None => {
+                        info!("Not probing {port}, another job uses it");
+                        continue;
+                    }
+                }
+            };
+
+            let port_mac = match job.settings.flash.tool {
+                FlashTool::Esptool => {
+                    let result = esptool_command(idf.get().await?, &["--port", port, "read_mac"])
+                        .output()
+                        .await?;
+                    String::from_utf8_lossy(&result.stdout).to_lowercase()
+                }
+                FlashTool::Native => match read_mac_native(job, port).await {
+                    Ok(port_mac) => port_mac,
+                    Err(e) => {
+                        debug!("No ESP32 answered on {port}: {e}");
+                        continue;
+                    }
+                },
+            };
+
+            if port_mac.contains(&mac.to_lowercase()) {
+                return Ok(LockedPort {
+                    path: port.clone(),
+                    _lock: lock,
+                });
+            }
         }
     }
 
     Err(Error::DeviceNotFound(format!(
-        "ESP32S3 with MAC address \"{}\"",
-        mac
+        "ESP32S3 {}",
+        discovery::describe(config)
     )))
 }
 
-async fn application_serial_port(sdk: &BuilderSdk) -> Result<&'static str> {
-    let board_config_name = sdk.board_config_name();
-
-    if board_config_name == "nuttx" {
-        Ok("/dev/ttyUSB0")
-    } else {
-        flashing_serial_port(sdk).await
-    }
+fn bootloader_reset(job: &Job, port: &str) -> Vec<ResetStep> {
+    job.settings
+        .flash
+        .bootloader_reset
+        .clone()
+        .unwrap_or_else(|| esp_loader::bootloader_reset(port))
 }
 
-async fn run_idf_command(sdk: &BuilderSdk, command: &str) -> Result<ExitStatus> {
-    let idf_version = idf_version(sdk);
-    let project_path = project_path(sdk);
-    Ok(Command::new("bash")
-        .arg("-c")
-        .arg(&format!(
-            ". /home/lvgl/esp/esp-idf{}/export.sh && idf.py -C {}  {}",
-            idf_version,
-            project_path.display(),
-            command
-        ))
-        .spawn()?
-        .wait()
-        .await?)
+async fn read_mac_native(job: &Job, port: &str) -> Result<String> {
+    let mut loader = EspLoader::connect(port, &bootloader_reset(job, port)).await?;
+    let mac = loader.read_mac().await?;
+    loader.hard_reset().await?;
+    Ok(mac)
 }
 
-async fn run_esptool_command(sdk: &BuilderSdk, command: &str) -> Result<ExitStatus> {
-    let idf_version = idf_version(sdk);
-    Ok(Command::new("bash")
-        .arg("-c")
-        .arg(&format!(
-            ". /home/lvgl/esp/esp-idf{}/export.sh && esptool.py {}",
-            idf_version, command
-        ))
-        .spawn()?
-        .wait()
-        .await?)
+fn flash_port_config(job: &Job) -> SerialPortConfig {
+    job.settings
+        .flash_port
+        .clone()
+        .unwrap_or_else(|| default_flash_port(&job.sdk))
 }
 
-async fn build_esp32s3_esp_idf(sdk: &BuilderSdk) -> Result<()> {
-    let result = run_idf_command(sdk, "--ccache build").await?;
+async fn flashing_serial_port(job: &Job, idf: &mut LazyIdfEnv<'_>) -> Result<LockedPort> {
+    find_serial_port(job, idf, &flash_port_config(job), None).await
+}
 
-    if !result.success() {
-        warn!(
-            "Build failed for ESP32. This happens when new source files are added. Performing a clean build"
-        );
-        // https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/tools/idf-py.html#select-the-target-chip-set-target
-        // https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/tools/idf-py.html#reconfigure-the-project-reconfigure
-        // `set-target` performs a clean build and reconfigures the project which is important in
-        // case files were added or removed from the source tree
-        let result = run_idf_command(sdk, &format!("set-target {}", sdk.board_name())).await?;
-        assert!(result.success(), "Clean Failed");
-        let result = run_idf_command(sdk, "--ccache build").await?;
-        assert!(result.success(), "Build Failed");
+/// The application port, `None` when the board prints to its flashing port.
+async fn application_serial_port(
+    job: &Job,
+    idf: &mut LazyIdfEnv<'_>,
+    flashing_port: &str,
+) -> Result<Option<LockedPort>> {
+    match job
+        .settings
+        .app_port
+        .clone()
+        .or_else(|| default_app_port(&job.sdk))
+    {
+        Some(config) => Ok(Some(
+            find_serial_port(job, idf, &config, Some(flashing_port)).await?,
+        )),
+        None => Ok(None),
     }
+}
 
-    Ok(())
+/// `idf.py` on the board's project, configured by `sdkconfig` instead of the project's own.
+fn idf_command(idf: &IdfEnv, sdk: &BuilderSdk, sdkconfig: &Path, args: &[&str]) -> Command {
+    let mut cmd = idf.command("idf.py");
+    cmd.arg("-C")
+        .arg(project_path(sdk))
+        .arg("-D")
+        .arg(format!("SDKCONFIG={}", sdkconfig.display()))
+        .args(args);
+    cmd
 }
 
-async fn nuttx_clean(sdk: &BuilderSdk) -> Result<()> {
-    let project_path = project_path(sdk);
-    let nuttx_path = project_path.join("nuttx");
-    let lvgl_path = project_path
-        .join("apps")
-        .join("graphics")
-        .join("lvgl")
-        .join("lvgl");
-
-    let _ = Command::new("make")
-        .arg("-C")
-        .arg(&nuttx_path)
-        .arg("distclean")
-        .spawn()?
-        .wait()
-        .await?;
+/// The sdkconfig the job builds with: the project's, or a copy next to the results with
+/// the board's lv_conf overrides applied, so they never leak into the checkout or other
+/// board configs sharing the project.
+/// `idf.py` only reconfigures when this path changes from one build to the next.
+fn sdkconfig_path(job: &Job) -> Result<PathBuf> {
+    let path = if job.settings.lv_conf.is_empty() {
+        project_path(&job.sdk).join("sdkconfig")
+    } else {
+        job.results_artifact_path("sdkconfig")
+    };
+    Ok(std::path::absolute(path)?)
+}
 
-    /* do this defensively in case distclean's rules weren't generated properly*/
-    let result = Command::new("bash")
-        .arg("-c")
-        .arg(&format!(
-            "rm -f $(find -H {} -name '*.o')",
-            lvgl_path.display()
-        ))
-        .spawn()?
-        .wait()
-        .await?;
-    assert!(result.success());
+fn esptool_command(idf: &IdfEnv, args: &[&str]) -> Command {
+    let mut cmd = idf.command("esptool.py");
+    cmd.args(args);
+    cmd
+}
 
-    Ok(())
+/// ELF of the flashed firmware, to decode crash backtraces.
+fn firmware_elf(job: &Job) -> Option<PathBuf> {
+    if job.sdk.board_config_name() == "nuttx" {
+        Some(nuttx::firmware_elf(job, &nuttx_config(job)))
+    } else {
+        firmware_size::find_elf(&project_path(&job.sdk).join("build"))
+    }
+}
+
+/// `addr2line` of the configured tool, or of the IDF toolchain.
+async fn addr2line_command(
+    job: &Job,
+    idf: &mut LazyIdfEnv<'_>,
+    elf: &Path,
+    addresses: &str,
+) -> Result<Command> {
+    let mut cmd = match &job.settings.addr2line {
+        Some(addr2line) => Command::new(addr2line),
+        None => idf.get().await?.command("xtensa-esp32s3-elf-addr2line"),
+    };
+    cmd.arg("-pfiaC").arg("-e").arg(elf);
+    cmd.args(addresses.split_whitespace());
+    Ok(cmd)
 }
 
-async fn build_esp32s3_nuttx(sdk: &BuilderSdk) -> Result<()> {
+async fn build_esp32s3_esp_idf(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
+    let idf = IdfEnv::load(job).await?;
     let project_path = project_path(sdk);
-    let nuttx_path = project_path.join("nuttx");
-    let lvgl_apps = project_path.join("apps").join("graphics").join("lvgl");
+    let build_path = project_path.join("build");
+    let manifest_path = build_path.join(source_manifest::FILE_NAME);
+    let sources = SourceManifest::scan(&lvgl_folder(&sdk.config_path())).await?;
 
-    let lvgl_path = lvgl_apps.join("lvgl");
-    let nuttx_lvgl_kconfig_path = lvgl_apps.join("Kconfig");
+    let project_sdkconfig = std::path::absolute(project_path.join("sdkconfig"))?;
+    let sdkconfig = sdkconfig_path(job)?;
 
-    let lvgl_kconfig_path = lvgl_path.join("Kconfig");
+    let set_target = !project_sdkconfig.exists();
+    if set_target {
+        // https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/tools/idf-py.html#select-the-target-chip-set-target
+        // `set-target` writes the sdkconfig for the chip and configures the project from scratch
+        job.run_step(
+            "idf.py set-target",
+            &mut idf_command(
+                &idf,
+                sdk,
+                &project_sdkconfig,
+                &["set-target", sdk.board_name()],
+            ),
+        )
+        .await?;
+    }
 
-    let source_bin_path = nuttx_path.join("nuttx.bin");
-    let target_bin_path = project_path.join("nuttx.bin");
-    let esp_hal_path =
-        std::path::absolute(project_path.join("espressif").join("esp-hal-3rdparty.git"))?;
+    // ESP-IDF's LVGL component takes the lv_conf.h options from LVGL's Kconfig.
+    let overrides = if job.settings.lv_conf.is_empty() {
+        BTreeMap::new()
+    } else {
+        let lvgl_kconfig =
+            tokio::fs::read_to_string(lvgl_folder(&sdk.config_path()).join("Kconfig")).await?;
+        kconfig::Symbols::parse(&lvgl_kconfig).translate(&job.settings.lv_conf)
+    };
 
-    info!("Cleaning nuttx build files");
-    nuttx_clean(sdk).await?;
+    if !overrides.is_empty() {
+        // The build rewrites the copy with the resolved configuration, so compare what it was
+        // made from instead. Rewriting it every time would reconfigure every build.
+        let project = tokio::fs::read_to_string(&project_sdkconfig).await?;
+        let updated = kconfig::apply(&project, &overrides);
+        let digest = format!("{:x}\n", md5::compute(&updated));
+        let digest_path = job.results_artifact_path("sdkconfig.md5");
+        let unchanged = sdkconfig.exists()
+            && tokio::fs::read_to_string(&digest_path).await.ok().as_ref() == Some(&digest);
+        if !unchanged {
+            info!("Applying lv_conf overrides to {}", sdkconfig.display());
+            tokio::fs::write(&sdkconfig, updated).await?;
+            tokio::fs::write(&digest_path, digest).await?;
+        }
+    }
 
-    info!("Setting up nuttx config");
-    {
-        let mut nuttx_lvgl_kconfig = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(nuttx_lvgl_kconfig_path)
-            .await?;
+    if !set_target && build_path.join("CMakeCache.txt").exists() {
+        // CMake only globs the LVGL sources when configuring, so files added or removed since
+        // the last successful build need a reconfigure, anything else builds incrementally.
+        // https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-guides/tools/idf-py.html#reconfigure-the-project-reconfigure
+        let reconfigure = match SourceManifest::read(&manifest_path).await {
+            Some(previous) => {
+                let (added, removed) = sources.changes(&previous);
+                let changed = !added.is_empty() || !removed.is_empty();
+                if changed {
+                    info!(
+                        "LVGL source files changed since the last build, {} added: [{}], {} removed: [{}]",
+                        added.len(),
+                        added.join(", "),
+                        removed.len(),
+                        removed.join(", ")
+                    );
+                }
+                changed
+            }
+            None => {
+                info!("No source manifest from a previous build");
+                true
+            }
+        };
 
-        let mut lvgl_kconfig = OpenOptions::new()
-            .read(true)
-            .open(lvgl_kconfig_path)
+        if reconfigure {
+            job.run_step(
+                "idf.py reconfigure",
+                &mut idf_command(&idf, sdk, &sdkconfig, &["reconfigure"]),
+            )
             .await?;
+        }
+    }
 
-        nuttx_lvgl_kconfig
-            .write_all(
-                br#"#
-# For a description of the syntax of this configuration file,
-# see the file kconfig-language.txt in the NuttX tools repository.
-#
+    let build = job
+        .run_step(
+            "idf.py build",
+            &mut idf_command(&idf, sdk, &sdkconfig, &["--ccache", "build"]),
+        )
+        .await;
+    // The build resolves the sdkconfig copy like `menuconfig` would.
+    if !overrides.is_empty()
+        && let Ok(resolved) = tokio::fs::read_to_string(&sdkconfig).await
+    {
+        kconfig::warn_dropped(&resolved, &overrides);
+    }
+    if let Err(Error::CommandFailed(message)) = build {
+        // Point at the first compiler error rather than only the exit status.
+        return Err(Error::CommandFailed(
+            match job
+                .diagnostics()
+                .into_iter()
+                .find(|d| d.severity == Severity::Error)
+            {
+                Some(d) => format!("{message}: {}:{}: {}", d.file, d.line, d.message),
+                None => message,
+            },
+        ));
+    }
+    build?;
 
-menuconfig GRAPHICS_LVGL
-	bool "Light and Versatile Graphic Library (LVGL)"
-	default n
-	---help---
-		Enable support for the LVGL GUI library.
+    sources.write(&manifest_path).await?;
 
-if GRAPHICS_LVGL
+    // idf.py names the ELF and map files after the project.
+    match firmware_size::find_elf(&build_path) {
+        Some(elf) => firmware_size::report(job, &elf, Some(&elf.with_extension("map"))).await?,
+        None => warn!("No ELF file found in the ESP-IDF build folder, skipping size report"),
+    }
 
-"#,
-            )
-            .await?;
+    Ok(())
+}
 
-        tokio::io::copy(&mut lvgl_kconfig, &mut nuttx_lvgl_kconfig).await?;
+/// NuttX settings of the board config, or the ones of the ESP32-S3-LCD-EV in the lab.
+fn nuttx_config(job: &Job) -> NuttxConfig {
+    job.settings.nuttx.clone().unwrap_or_else(|| NuttxConfig {
+        target: "esp32s3-lcd-ev:lvgl".to_string(),
+        project: PathBuf::from("lv_nuttx"),
+        lvgl_app_dir: PathBuf::from("graphics/lvgl"),
+        app: "my_lvgl_app".to_string(),
+        defconfig: BTreeMap::new(),
+        kconfig: NuttxKconfig::default(),
+        env: BTreeMap::from([(
+            "ESP_HAL_3RDPARTY_URL".to_string(),
+            "lvgl@127.0.0.1:{project}/espressif/esp-hal-3rdparty.git".to_string(),
+        )]),
+        flash: Vec::new(),
+    })
+}
 
-        nuttx_lvgl_kconfig
-            .write_all(
-                br#"
-config LV_OPTLEVEL
-	string "Customize compilation optimization level"
-	default ""
+pub async fn build_esp32s3(job: &Job) -> Result<()> {
+    if job.sdk.board_config_name() == "nuttx" {
+        nuttx::build(job, &nuttx_config(job)).await
+    } else {
+        build_esp32s3_esp_idf(job).await
+    }
+}
 
-endif # GRAPHICS_LVGL
-"#,
-            )
-            .await?;
+/// Write the firmware with `idf.py flash`, or `esptool.py` for NuttX images.
+async fn flash_esptool(job: &Job, idf: &IdfEnv, flashing_port: &str) -> Result<()> {
+    if job.sdk.board_config_name() == "nuttx" {
+        let bin_path = nuttx::firmware_bin(job, &nuttx_config(job));
+        let bin_path = bin_path.to_string_lossy();
+        let esptool = |baud: u32, command: &'static str| {
+            let baud = baud.to_string();
+            let mut esptool = esptool_command(
+                idf,
+                &[
+                    "-c",
+                    "esp32s3",
+                    "-p",
+                    flashing_port,
+                    "-b",
+                    &baud,
+                    command,
+                    "-fs",
+                    "detect",
+                    "-fm",
+                    "dio",
+                    "-ff",
+                    "40m",
+                    "0x0000",
+                    &bin_path,
+                ],
+            );
+            async move {
+                job.run_checked(&format!("esptool.py {command}"), &mut esptool)
+                    .await
+            }
+        };
+        flash::flash(
+            job,
+            "esptool.py write_flash",
+            921_600,
+            |baud| esptool(baud, "write_flash"),
+            |baud| esptool(baud, "verify_flash"),
+        )
+        .await
+    } else {
+        let sdkconfig = sdkconfig_path(job)?;
+        // `flash_args` lists the images `idf.py flash` writes, relative to the build folder.
+        let build_path = std::path::absolute(project_path(&job.sdk).join("build"))?;
+        flash::flash(
+            job,
+            "idf.py flash",
+            460_800,
+            |baud| {
+                let mut flash = idf_command(
+                    idf,
+                    &job.sdk,
+                    &sdkconfig,
+                    &[
+                        "--port",
+                        flashing_port,
+                        "--baud",
+                        &baud.to_string(),
+                        "flash",
+                    ],
+                );
+                async move { job.run_checked("idf.py flash", &mut flash).await }
+            },
+            |baud| {
+                let mut command = esptool_command(
+                    idf,
+                    &[
+                        "-c",
+                        "esp32s3",
+                        "-p",
+                        flashing_port,
+                        "-b",
+                        &baud.to_string(),
+                        "verify_flash",
+                        "@flash_args",
+                    ],
+                );
+                command.current_dir(&build_path);
+                async move {
+                    job.run_checked("esptool.py verify_flash", &mut command)
+                        .await
+                }
+            },
+        )
+        .await
     }
+}
 
-    info!("Building nuttx");
-    let result = Command::new("bash")
-        .arg("-c")
-        .arg(&format!(
-            "cd {} \
-            && ./tools/configure.sh -l esp32s3-lcd-ev:lvgl \
-            && ESP_HAL_3RDPARTY_URL='lvgl@127.0.0.1:{}' make -j$(nproc) nuttx \
-            ",
-            nuttx_path.display(),
-            esp_hal_path.display(),
-        ))
-        .spawn()?
-        .wait()
-        .await?;
-    assert!(result.success());
+/// Image written by the native flasher.
+struct FlashImage {
+    offset: u32,
+    path: PathBuf,
+}
 
-    info!("Saving binary file");
-    tokio::fs::copy(source_bin_path, target_bin_path).await?;
+/// What `idf.py flash` writes, from the build's `flasher_args.json`.
+#[derive(Deserialize)]
+struct FlasherArgs {
+    flash_files: BTreeMap<String, PathBuf>,
+    flash_settings: FlashSettings,
+}
 
-    /* we need to clean this build so the lvgl dir isn't polluted with object files*/
-    info!("Cleaning nuttx build files");
-    nuttx_clean(sdk).await?;
+#[derive(Deserialize)]
+struct FlashSettings {
+    flash_size: String,
+}
 
-    Ok(())
+/// Images to write and the flash size to configure, for the native flasher.
+async fn flash_images(job: &Job) -> Result<(Vec<FlashImage>, u32)> {
+    let configured_size = job.settings.flash.flash_size.as_deref();
+    let invalid_size = |size: &str| Error::ConfigError(format!("Invalid flash size \"{size}\""));
+
+    let build_path = project_path(&job.sdk).join("build");
+    let args_path = build_path.join("flasher_args.json");
+    let args: FlasherArgs = serde_json::from_str(&tokio::fs::read_to_string(&args_path).await?)
+        .map_err(|e| Error::FlashFailed(format!("Can't read {}: {e}", args_path.display())))?;
+
+    let images = args
+        .flash_files
+        .into_iter()
+        .map(|(offset, path)| {
+            let offset = offset
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| {
+                    Error::FlashFailed(format!(
+                        "Invalid offset \"{offset}\" in {}",
+                        args_path.display()
+                    ))
+                })?;
+            Ok(FlashImage {
+                offset,
+                path: build_path.join(path),
+            })
+        })
+        .collect::<Result<_>>()?;
+    let size = configured_size.unwrap_or(&args.flash_settings.flash_size);
+    let size = esp_loader::parse_flash_size(size).ok_or_else(|| invalid_size(size))?;
+
+    Ok((images, size))
 }
 
-pub async fn build_esp32s3(sdk: &BuilderSdk) -> Result<()> {
-    if sdk.board_config_name() == "nuttx" {
-        build_esp32s3_nuttx(sdk).await
-    } else {
-        build_esp32s3_esp_idf(sdk).await
-    }
+/// Connect to the ROM bootloader of the board on `port`, at `baud` with its flash attached.
+async fn connect_loader(job: &Job, port: &str, baud: u32, flash_size: u32) -> Result<EspLoader> {
+    let mut loader = EspLoader::connect(port, &bootloader_reset(job, port)).await?;
+    loader.change_baud(baud).await?;
+    loader.attach_flash(flash_size).await?;
+    Ok(loader)
 }
 
-pub async fn run_esp32s3(sdk: &BuilderSdk) -> Result<()> {
+/// Write the firmware through the ROM bootloader, without `esptool.py`.
+async fn flash_native(job: &Job, port: &str) -> Result<()> {
+    let (images, flash_size) = flash_images(job).await?;
+    let images = &images;
+
+    flash::flash(
+        job,
+        "native write_flash",
+        460_800,
+        |baud| async move {
+            let mut loader = connect_loader(job, port, baud, flash_size).await?;
+            for image in images {
+                info!("Writing {} at 0x{:x}", image.path.display(), image.offset);
+                let data = tokio::fs::read(&image.path).await?;
+                loader.write_flash(image.offset, &data).await?;
+            }
+            loader.hard_reset().await
+        },
+        |baud| async move {
+            let mut loader = connect_loader(job, port, baud, flash_size).await?;
+            for image in images {
+                let data = tokio::fs::read(&image.path).await?;
+                let expected = format!("{:x}", md5::compute(&data));
+                let actual = loader.flash_md5(image.offset, data.len()).await?;
+                if actual != expected {
+                    return Err(Error::EspLoader(format!(
+                        "Flash at 0x{:x} doesn't match {}: MD5 {actual}, expected {expected}",
+                        image.offset,
+                        image.path.display()
+                    )));
+                }
+            }
+            loader.hard_reset().await
+        },
+    )
+    .await
+}
+
+pub async fn run_esp32s3(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
     let board_config_name = sdk.board_config_name();
-    let project_path = project_path(sdk);
-    let results_p = results_path(&sdk.config_path(), "esp32s3");
+    let results_p = job.results_path();
 
     let _ = std::fs::remove_file(&results_p);
 
-    let flashing_port = flashing_serial_port(sdk).await?;
+    // nuttx.bin keeps the flash mode, frequency and size of its build in the image header, only
+    // `esptool.py write_flash -fs detect -fm dio -ff 40m` rewrites them for the board.
+    if board_config_name == "nuttx" && matches!(job.settings.flash.tool, FlashTool::Native) {
+        return Err(Error::ConfigError(
+            "The native flasher can't write NuttX images, use tool = \"esptool\"".to_string(),
+        ));
+    }
 
-    if board_config_name == "nuttx" {
-        let bin_path = project_path.join("nuttx.bin");
-        let result = run_esptool_command(
-            sdk,
-            &format!(
-                "-c esp32s3 -p {} -b 921600 write_flash -fs detect -fm dio -ff \"40m\" 0x0000 {}",
-                flashing_port,
-                bin_path.display()
-            ),
-        )
-        .await?;
-        assert!(result.success());
-    } else {
-        let result = run_idf_command(sdk, &format!("--port {} flash", flashing_port)).await?;
+    let _lock = DeviceLock::acquire(job, &discovery::device_id(&flash_port_config(job))?).await?;
 
-        assert!(result.success());
+    // Only esptool and the IDF's addr2line need the environment, the native flasher doesn't.
+    let mut idf = LazyIdfEnv::new(job);
+    let flashing_port = flashing_serial_port(job, &mut idf).await?;
+
+    match job.settings.flash.tool {
+        FlashTool::Esptool => flash_esptool(job, idf.get().await?, &flashing_port.path).await?,
+        FlashTool::Native => flash_native(job, &flashing_port.path).await?,
     }
 
-    let application_port = application_serial_port(sdk).await?;
+    let application_port = application_serial_port(job, &mut idf, &flashing_port.path).await?;
+    let application_port_path = application_port
+        .as_ref()
+        .map_or(&flashing_port.path, |port| &port.path);
 
-    let mut port = tokio_serial::new(application_port, 115_200)
-        .timeout(Duration::from_secs(120))
+    let mut port = tokio_serial::new(application_port_path, 115_200)
+        .timeout(Duration::from_secs(job.settings.capture.idle_timeout_secs))
         .open_native_async()?;
 
+    let mut settings = job.settings.clone();
     if board_config_name == "nuttx" {
-        /* just in case the nuttx prompt isn't ready yet */
-        sleep(Duration::from_millis(2000)).await;
-
-        port.write_all(b"my_lvgl_app\n").await?;
-        port.flush().await?;
+        nuttx::console_defaults(&mut settings, &nuttx_config(job).app);
     }
 
-    let mut reader = BufReader::new(port);
-
     let mut output = String::new();
-    loop {
-        let mut buffer = Vec::new();
-        let n = reader.read_until(b'\n', &mut buffer).await?;
-
-        if n == 0 {
-            return Err(Error::TimeoutWaitingForBenchmarkToEnd(output));
-        }
-
-        if let Ok(line) = String::from_utf8(buffer) {
-            output.push_str(&line);
-            if output.contains("Benchmark Over") {
-                std::fs::write(results_p, output)?;
-                return Ok(());
+    let result = session(
+        &mut port,
+        &settings,
+        &results_p,
+        &job.results_artifact_path("transcript.log"),
+        &mut output,
+    )
+    .await;
+
+    let result = match (result, firmware_elf(job)) {
+        (Err(Error::DeviceCrashed(pattern, mut context)), Some(elf)) => {
+            let decoded = backtrace::decode(&context, &elf, async |addresses| {
+                addr2line_command(job, &mut idf, &elf, addresses).await
+            })
+            .await;
+            if let Some(decoded) = decoded {
+                // Decoded after the session wrote the results, so append it there as well.
+                context.push_str(&decoded);
+                output.push_str(&decoded);
+                std::fs::write(&results_p, &output)?;
             }
+            Err(Error::DeviceCrashed(pattern, context))
         }
-    }
+        (result, _) => result,
+    };
+
+    job.record_output(&output);
+    result
 }
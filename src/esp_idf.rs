@@ -0,0 +1,199 @@
+//! ESP-IDF installations and the environment their `export.sh` sets up.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use tokio::process::Command;
+use tracing::info;
+
+use crate::config::IdfInstallation;
+use crate::job::Job;
+use crate::prelude::*;
+
+/// Environment exported by an ESP-IDF installation, captured once per job so commands
+/// run directly instead of sourcing `export.sh` in a new shell every time.
+pub struct IdfEnv {
+    vars: HashMap<String, String>,
+}
+
+impl IdfEnv {
+    /// Validate the installation selected for the job's board config and capture its environment.
+    pub async fn load(job: &Job) -> Result<Self> {
+        let installation = installation(job)?;
+
+        job.step("esp-idf environment", async {
+            let version = read_version(&installation.path).await?;
+            if let Some(expected) = &installation.version
+                && version != *expected
+                && !version.starts_with(&format!("{expected}."))
+            {
+                return Err(Error::ConfigError(format!(
+                    "ESP-IDF at {} is version {version}, expected {expected}",
+                    installation.path.display()
+                )));
+            }
+
+            info!(
+                "Using ESP-IDF {version} from {}",
+                installation.path.display()
+            );
+            let vars = export(&installation.path).await?;
+
+            Ok(Self { vars })
+        })
+        .await
+    }
+
+    /// `program` set up to run with the ESP-IDF tools and Python environment.
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        command.env_clear().envs(&self.vars);
+        command
+    }
+}
+
+/// The job's [`IdfEnv`], loaded the first time a step needs it, so runs that only use the
+/// native flasher never source `export.sh`.
+pub struct LazyIdfEnv<'a> {
+    job: &'a Job,
+    env: Option<IdfEnv>,
+}
+
+impl<'a> LazyIdfEnv<'a> {
+    pub fn new(job: &'a Job) -> Self {
+        Self { job, env: None }
+    }
+
+    pub async fn get(&mut self) -> Result<&IdfEnv> {
+        if self.env.is_none() {
+            self.env = Some(IdfEnv::load(self.job).await?);
+        }
+        Ok(self.env.as_ref().expect("the environment was just loaded"))
+    }
+}
+
+fn installation(job: &Job) -> Result<IdfInstallation> {
+    match &job.settings.esp_idf {
+        Some(name) => job
+            .config
+            .esp_idf
+            .installations
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::ConfigError(format!("Unknown ESP-IDF installation \"{name}\""))),
+        None => Ok(default_installation(job.sdk.board_config_name())),
+    }
+}
+
+/// Installations of the lab hosts, used when the board config doesn't select one.
+fn default_installation(board_config_name: &str) -> IdfInstallation {
+    let version = if board_config_name == "eve" {
+        "5.3.1"
+    } else {
+        "5.2.5"
+    };
+
+    IdfInstallation {
+        path: format!("/home/lvgl/esp/esp-idf{version}").into(),
+        version: Some(version.to_string()),
+    }
+}
+
+/// Read `major.minor.patch` from `tools/cmake/version.cmake`, which every ESP-IDF release ships.
+async fn read_version(idf_path: &Path) -> Result<String> {
+    let version_file = idf_path.join("tools").join("cmake").join("version.cmake");
+    let contents = tokio::fs::read_to_string(&version_file)
+        .await
+        .map_err(|e| {
+            Error::ConfigError(format!(
+                "No ESP-IDF installation at {}: {e}",
+                idf_path.display()
+            ))
+        })?;
+
+    let component = |name: &str| {
+        contents.lines().find_map(|line| {
+            line.trim()
+                .strip_prefix(&format!("set({name} "))?
+                .strip_suffix(')')
+                .map(|value| value.trim().to_string())
+        })
+    };
+
+    match (
+        component("IDF_VERSION_MAJOR"),
+        component("IDF_VERSION_MINOR"),
+        component("IDF_VERSION_PATCH"),
+    ) {
+        (Some(major), Some(minor), Some(patch)) => Ok(format!("{major}.{minor}.{patch}")),
+        _ => Err(Error::ConfigError(format!(
+            "Can't read the ESP-IDF version from {}",
+            version_file.display()
+        ))),
+    }
+}
+
+/// Source `export.sh` once and capture the resulting environment.
+async fn export(idf_path: &Path) -> Result<HashMap<String, String>> {
+    let output = Command::new("bash")
+        .arg("-c")
+        .arg(r#". "$0/export.sh" > /dev/null && env -0"#)
+        .arg(idf_path)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "{}/export.sh exited with {}: {}",
+            idf_path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter_map(|var| var.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::test_dir;
+
+    fn fake_installation(name: &str, version_cmake: &str) -> std::path::PathBuf {
+        let idf_path = test_dir(name);
+        let cmake = idf_path.join("tools").join("cmake");
+        std::fs::create_dir_all(&cmake).unwrap();
+        std::fs::write(cmake.join("version.cmake"), version_cmake).unwrap();
+        idf_path
+    }
+
+    #[tokio::test]
+    async fn version_from_version_cmake() {
+        let idf_path = fake_installation(
+            "esp-idf-version",
+            "set(IDF_VERSION_MAJOR 5)\nset(IDF_VERSION_MINOR 3)\n  set(IDF_VERSION_PATCH 1)\n",
+        );
+        assert_eq!(read_version(&idf_path).await.unwrap(), "5.3.1");
+    }
+
+    #[tokio::test]
+    async fn unreadable_version() {
+        let idf_path = fake_installation(
+            "esp-idf-no-patch",
+            "set(IDF_VERSION_MAJOR 5)\nset(IDF_VERSION_MINOR 3)\n",
+        );
+        assert!(matches!(
+            read_version(&idf_path).await,
+            Err(Error::ConfigError(_))
+        ));
+        assert!(matches!(
+            read_version(&idf_path.join("missing")).await,
+            Err(Error::ConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,542 @@
+//! Client of the ESP32-S3 ROM serial bootloader, to probe and flash boards without `esptool.py`.
+//!
+//! Implements the subset of the protocol `esptool.py` uses against the ROM (no stub loader):
+//! SLIP framed commands, reading the MAC from the eFuses, uncompressed flash writes and MD5 checks.
+//! https://docs.espressif.com/projects/esptool/en/latest/esp32s3/advanced-topics/serial-protocol.html
+
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Instant, sleep, timeout_at};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tracing::{debug, info};
+
+use crate::config::ResetStep;
+use crate::prelude::*;
+use crate::reset::reset;
+
+const FLASH_BEGIN: u8 = 0x02;
+const FLASH_DATA: u8 = 0x03;
+const FLASH_END: u8 = 0x04;
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0a;
+const SPI_SET_PARAMS: u8 = 0x0b;
+const SPI_ATTACH: u8 = 0x0d;
+const CHANGE_BAUDRATE: u8 = 0x0f;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// The ROM loader talks at this rate until told otherwise.
+const ROM_BAUD: u32 = 115_200;
+
+/// Data written per `FLASH_DATA` command, the most the ROM loader accepts.
+const FLASH_WRITE_SIZE: usize = 0x400;
+
+/// Seed of the XOR checksum of `FLASH_DATA` payloads.
+const CHECKSUM_SEED: u8 = 0xef;
+
+/// eFuse words holding the factory MAC address.
+const MAC_EFUSE_REG: u32 = 0x6000_7044;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(3);
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+const SYNC_ATTEMPTS: usize = 10;
+
+/// Time allowed per MB for erasing before a write and for hashing the flash.
+const ERASE_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
+
+/// Reset through the auto-program circuit of most dev boards: EN is wired to RTS and GPIO0 to DTR.
+pub fn classic_reset() -> Vec<ResetStep> {
+    let step = |dtr, rts, delay_ms| ResetStep {
+        dtr: Some(dtr),
+        rts: Some(rts),
+        delay_ms,
+    };
+    vec![
+        step(false, true, 100),
+        step(true, false, 50),
+        step(false, false, 0),
+    ]
+}
+
+/// Reset through the USB-Serial/JTAG peripheral of the chip, which decodes the DTR/RTS
+/// transitions itself, so the order of the line changes matters.
+pub fn usb_jtag_serial_reset() -> Vec<ResetStep> {
+    let dtr = |dtr, delay_ms| ResetStep {
+        dtr: Some(dtr),
+        rts: None,
+        delay_ms,
+    };
+    let rts = |rts, delay_ms| ResetStep {
+        dtr: None,
+        rts: Some(rts),
+        delay_ms,
+    };
+    vec![
+        rts(false, 0),
+        dtr(false, 100),
+        dtr(true, 0),
+        rts(false, 100),
+        rts(true, 0),
+        dtr(false, 0),
+        rts(true, 100),
+        dtr(false, 0),
+        rts(false, 0),
+    ]
+}
+
+/// Sequence resetting the board on `path` into its bootloader, depending on how it's connected.
+pub fn bootloader_reset(path: &str) -> Vec<ResetStep> {
+    let usb_jtag_serial = tokio_serial::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .any(|port| {
+            port.port_name == path
+                && matches!(
+                    port.port_type,
+                    tokio_serial::SerialPortType::UsbPort(info)
+                        if info.vid == 0x303a && info.pid == 0x1001
+                )
+        });
+
+    if usb_jtag_serial {
+        usb_jtag_serial_reset()
+    } else {
+        classic_reset()
+    }
+}
+
+/// Parse a flash size as written by `idf.py` and `esptool.py`, e.g. `16MB`.
+pub fn parse_flash_size(size: &str) -> Option<u32> {
+    let megabytes: u32 = size.strip_suffix("MB")?.parse().ok()?;
+    megabytes.checked_mul(1024 * 1024)
+}
+
+pub struct EspLoader {
+    port: SerialStream,
+    reader: SlipReader,
+}
+
+impl EspLoader {
+    /// Open `path`, reset the board into its bootloader through `reset_sequence` and sync with it.
+    pub async fn connect(path: &str, reset_sequence: &[ResetStep]) -> Result<Self> {
+        let port = tokio_serial::new(path, ROM_BAUD).open_native_async()?;
+        let mut loader = Self::new(port);
+        reset(&mut loader.port, reset_sequence).await?;
+        loader.sync().await?;
+        Ok(loader)
+    }
+
+    pub fn new(port: SerialStream) -> Self {
+        Self {
+            port,
+            reader: SlipReader::default(),
+        }
+    }
+
+    /// Wait for the bootloader to answer `SYNC`, which also lets it detect the baud rate.
+    pub async fn sync(&mut self) -> Result<()> {
+        let mut payload = vec![0x07, 0x07, 0x12, 0x20];
+        payload.extend([0x55; 32]);
+
+        for attempt in 1..=SYNC_ATTEMPTS {
+            match self.command(SYNC, &payload, 0, SYNC_TIMEOUT).await {
+                Ok(_) => {
+                    // The ROM answers a SYNC several times, drop the extra answers.
+                    sleep(SYNC_TIMEOUT).await;
+                    self.drain().await;
+                    return Ok(());
+                }
+                Err(e) => debug!("Sync attempt {attempt}/{SYNC_ATTEMPTS} failed: {e}"),
+            }
+        }
+
+        Err(Error::EspLoader(format!(
+            "No answer from the ROM bootloader after {SYNC_ATTEMPTS} sync attempts"
+        )))
+    }
+
+    pub async fn read_reg(&mut self, address: u32) -> Result<u32> {
+        let (value, _) = self
+            .command(READ_REG, &address.to_le_bytes(), 0, COMMAND_TIMEOUT)
+            .await?;
+        Ok(value)
+    }
+
+    /// Factory MAC address, formatted like `esptool.py read_mac` prints it.
+    pub async fn read_mac(&mut self) -> Result<String> {
+        let low = self.read_reg(MAC_EFUSE_REG).await?.to_be_bytes();
+        let high = self.read_reg(MAC_EFUSE_REG + 4).await?.to_be_bytes();
+        let mac = [high[2], high[3], low[0], low[1], low[2], low[3]];
+        Ok(mac
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(":"))
+    }
+
+    /// Switch the bootloader and the port to `baud`.
+    pub async fn change_baud(&mut self, baud: u32) -> Result<()> {
+        if baud == ROM_BAUD {
+            return Ok(());
+        }
+        let mut payload = baud.to_le_bytes().to_vec();
+        payload.extend(0u32.to_le_bytes());
+        self.command(CHANGE_BAUDRATE, &payload, 0, COMMAND_TIMEOUT)
+            .await?;
+        self.port.set_baud_rate(baud)?;
+        sleep(Duration::from_millis(50)).await;
+        self.drain().await;
+        Ok(())
+    }
+
+    /// Attach the SPI flash and tell the bootloader its size.
+    pub async fn attach_flash(&mut self, flash_size: u32) -> Result<()> {
+        self.command(SPI_ATTACH, &[0; 8], 0, COMMAND_TIMEOUT)
+            .await?;
+
+        let params: Vec<u8> = [0, flash_size, 0x10000, 0x1000, 0x100, 0xffff]
+            .iter()
+            .flat_map(|value: &u32| value.to_le_bytes())
+            .collect();
+        self.command(SPI_SET_PARAMS, &params, 0, COMMAND_TIMEOUT)
+            .await?;
+        Ok(())
+    }
+
+    /// Erase and write `image` at `offset`, as is.
+    pub async fn write_flash(&mut self, offset: u32, image: &[u8]) -> Result<()> {
+        let blocks = image.len().div_ceil(FLASH_WRITE_SIZE);
+        info!(
+            "Writing {} bytes at 0x{offset:08x} in {blocks} blocks",
+            image.len()
+        );
+
+        let mut begin = Vec::new();
+        for value in [
+            image.len() as u32,
+            blocks as u32,
+            FLASH_WRITE_SIZE as u32,
+            offset,
+            0,
+        ] {
+            begin.extend(value.to_le_bytes());
+        }
+        self.command(
+            FLASH_BEGIN,
+            &begin,
+            0,
+            COMMAND_TIMEOUT.max(per_mb(ERASE_TIMEOUT_PER_MB, image.len())),
+        )
+        .await?;
+
+        for (sequence, chunk) in image.chunks(FLASH_WRITE_SIZE).enumerate() {
+            let mut block = chunk.to_vec();
+            block.resize(FLASH_WRITE_SIZE, 0xff);
+
+            let mut payload = Vec::with_capacity(16 + block.len());
+            for value in [block.len() as u32, sequence as u32, 0, 0] {
+                payload.extend(value.to_le_bytes());
+            }
+            payload.extend(&block);
+            self.command(FLASH_DATA, &payload, checksum(&block), COMMAND_TIMEOUT)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// MD5 of `len` bytes of flash at `offset`, as lowercase hex.
+    pub async fn flash_md5(&mut self, offset: u32, len: usize) -> Result<String> {
+        let mut payload = Vec::new();
+        for value in [offset, len as u32, 0, 0] {
+            payload.extend(value.to_le_bytes());
+        }
+        let (_, data) = self
+            .command(
+                SPI_FLASH_MD5,
+                &payload,
+                0,
+                COMMAND_TIMEOUT.max(per_mb(MD5_TIMEOUT_PER_MB, len)),
+            )
+            .await?;
+
+        // The ROM sends the digest as 32 hex characters.
+        let digest = data.get(..32).ok_or_else(|| {
+            Error::EspLoader(format!("Short SPI_FLASH_MD5 response: {data:02x?}"))
+        })?;
+        Ok(String::from_utf8_lossy(digest).to_lowercase())
+    }
+
+    /// Leave the bootloader and reboot into the flashed application through EN.
+    pub async fn hard_reset(mut self) -> Result<()> {
+        // Stay in the loader, the reset below starts the application.
+        self.command(FLASH_END, &1u32.to_le_bytes(), 0, COMMAND_TIMEOUT)
+            .await?;
+        self.port.write_request_to_send(true)?;
+        sleep(Duration::from_millis(100)).await;
+        self.port.write_request_to_send(false)?;
+        Ok(())
+    }
+
+    /// Send `command` and wait for its response, returning the response's value and data.
+    async fn command(
+        &mut self,
+        command: u8,
+        payload: &[u8],
+        checksum: u8,
+        timeout: Duration,
+    ) -> Result<(u32, Vec<u8>)> {
+        let mut packet = vec![0x00, command];
+        packet.extend((payload.len() as u16).to_le_bytes());
+        packet.extend(u32::from(checksum).to_le_bytes());
+        packet.extend(payload);
+        self.port.write_all(&slip_encode(&packet)).await?;
+        self.port.flush().await?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let frame = self
+                .reader
+                .read_frame(&mut self.port, deadline)
+                .await
+                .ok_or_else(|| {
+                    Error::EspLoader(format!(
+                        "Timeout waiting for the response to 0x{command:02x}"
+                    ))
+                })?;
+
+            // Responses are direction 1, the command, the data size, a value and the data,
+            // ending with the status bytes. Anything else is a leftover of a previous command.
+            if frame.len() < 12 || frame[0] != 0x01 || frame[1] != command {
+                continue;
+            }
+            let value = u32::from_le_bytes(frame[4..8].try_into().unwrap());
+            let data = &frame[8..];
+            let (data, status) = data.split_at(data.len() - 4);
+            if status[0] != 0 {
+                return Err(Error::EspLoader(format!(
+                    "Command 0x{command:02x} failed with error 0x{:02x}",
+                    status[1]
+                )));
+            }
+            return Ok((value, data.to_vec()));
+        }
+    }
+
+    /// Drop whatever the bootloader sent and nobody waits for.
+    async fn drain(&mut self) {
+        let deadline = || Instant::now() + Duration::from_millis(10);
+        while self
+            .reader
+            .read_frame(&mut self.port, deadline())
+            .await
+            .is_some()
+        {}
+        self.reader.pending.clear();
+    }
+}
+
+/// Splits what the port reads into SLIP frames.
+#[derive(Default)]
+struct SlipReader {
+    /// Bytes read past the last frame.
+    pending: Vec<u8>,
+}
+
+impl SlipReader {
+    /// Next SLIP frame, decoded, `None` if none came before `deadline`.
+    async fn read_frame(
+        &mut self,
+        port: &mut (impl AsyncRead + Unpin),
+        deadline: Instant,
+    ) -> Option<Vec<u8>> {
+        loop {
+            // Anything before the first delimiter is boot log output.
+            if let Some(start) = self.pending.iter().position(|&byte| byte == 0xc0) {
+                self.pending.drain(..start);
+                if let Some(len) = self.pending[1..].iter().position(|&byte| byte == 0xc0) {
+                    let frame: Vec<u8> = self.pending.drain(..=len + 1).collect();
+                    let frame = &frame[1..frame.len() - 1];
+                    if frame.is_empty() {
+                        // Two delimiters in a row, the second one starts the next frame.
+                        self.pending.insert(0, 0xc0);
+                        continue;
+                    }
+                    return Some(slip_decode(frame));
+                }
+            } else {
+                self.pending.clear();
+            }
+
+            let mut buffer = [0u8; 256];
+            match timeout_at(deadline, port.read(&mut buffer)).await {
+                Ok(Ok(read)) if read > 0 => self.pending.extend(&buffer[..read]),
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn per_mb(timeout: Duration, len: usize) -> Duration {
+    timeout.mul_f64(len as f64 / (1024.0 * 1024.0))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(CHECKSUM_SEED, |checksum, byte| checksum ^ byte)
+}
+
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + 2);
+    encoded.push(0xc0);
+    for &byte in packet {
+        match byte {
+            0xc0 => encoded.extend([0xdb, 0xdc]),
+            0xdb => encoded.extend([0xdb, 0xdd]),
+            _ => encoded.push(byte),
+        }
+    }
+    encoded.push(0xc0);
+    encoded
+}
+
+fn slip_decode(frame: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(&byte) = bytes.next() {
+        match (byte, bytes.as_slice().first()) {
+            (0xdb, Some(0xdc)) => {
+                decoded.push(0xc0);
+                bytes.next();
+            }
+            (0xdb, Some(0xdd)) => {
+                decoded.push(0xdb);
+                bytes.next();
+            }
+            _ => decoded.push(byte),
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    /// ROM bootloader with 64 KiB of flash on the board's end of a pty.
+    fn spawn_rom(mac: [u8; 6]) -> (EspLoader, JoinHandle<Vec<u8>>) {
+        let (mut board, host) = SerialStream::pair().expect("failed to open a pty pair");
+
+        let handle = tokio::spawn(async move {
+            let mut reader = SlipReader::default();
+            let mut flash = vec![0xffu8; 0x10000];
+            let mut write_offset = 0;
+            board
+                .write_all(b"ESP-ROM:esp32s3-20210327\r\nwaiting for download\r\n")
+                .await
+                .unwrap();
+
+            let deadline = || Instant::now() + Duration::from_secs(5);
+            while let Some(packet) = reader.read_frame(&mut board, deadline()).await {
+                let command = packet[1];
+                let payload = &packet[8..];
+                let word = |index: usize| {
+                    u32::from_le_bytes(payload[index * 4..index * 4 + 4].try_into().unwrap())
+                };
+                let mut data = Vec::new();
+                let value = match command {
+                    READ_REG if word(0) == MAC_EFUSE_REG => {
+                        u32::from_be_bytes(mac[2..].try_into().unwrap())
+                    }
+                    READ_REG => u32::from_be_bytes([0, 0, mac[0], mac[1]]),
+                    FLASH_BEGIN => {
+                        write_offset = word(3) as usize;
+                        0
+                    }
+                    FLASH_DATA => {
+                        let len = word(0) as usize;
+                        assert_eq!(
+                            u32::from(checksum(&payload[16..16 + len])),
+                            u32::from_le_bytes(packet[4..8].try_into().unwrap())
+                        );
+                        let sequence = word(1) as usize;
+                        let start = write_offset + sequence * FLASH_WRITE_SIZE;
+                        flash[start..start + len].copy_from_slice(&payload[16..16 + len]);
+                        0
+                    }
+                    SPI_FLASH_MD5 => {
+                        let (offset, len) = (word(0) as usize, word(1) as usize);
+                        data = format!("{:x}", md5::compute(&flash[offset..offset + len]))
+                            .into_bytes();
+                        0
+                    }
+                    _ => 0,
+                };
+
+                let mut response = vec![0x01, command];
+                response.extend(((data.len() + 4) as u16).to_le_bytes());
+                response.extend(value.to_le_bytes());
+                response.extend(&data);
+                response.extend([0; 4]);
+                board.write_all(&slip_encode(&response)).await.unwrap();
+                if command == SYNC {
+                    // The ROM answers every SYNC more than once.
+                    board.write_all(&slip_encode(&response)).await.unwrap();
+                }
+                if command == FLASH_END {
+                    break;
+                }
+            }
+            flash
+        });
+
+        (EspLoader::new(host), handle)
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        let packet = [0x01, 0xc0, 0xdb, 0xdc, 0xc0];
+        let encoded = slip_encode(&packet);
+        assert_eq!(
+            encoded,
+            [0xc0, 0x01, 0xdb, 0xdc, 0xdb, 0xdd, 0xdc, 0xdb, 0xdc, 0xc0]
+        );
+        assert_eq!(slip_decode(&encoded[1..encoded.len() - 1]), packet);
+    }
+
+    #[tokio::test]
+    async fn reads_mac() {
+        let (mut loader, _rom) = spawn_rom([0x34, 0x85, 0x18, 0x6c, 0xf6, 0xdc]);
+        loader.sync().await.unwrap();
+        assert_eq!(loader.read_mac().await.unwrap(), "34:85:18:6c:f6:dc");
+    }
+
+    #[tokio::test]
+    async fn writes_and_verifies_flash() {
+        let (mut loader, rom) = spawn_rom([0; 6]);
+        // Covers SLIP escaping and a partial last block.
+        let image: Vec<u8> = (0..FLASH_WRITE_SIZE * 2 + 100)
+            .map(|i| [0xc0, 0xdb, i as u8][i % 3])
+            .collect();
+
+        loader.sync().await.unwrap();
+        loader.attach_flash(0x10000).await.unwrap();
+        loader.write_flash(0x1000, &image).await.unwrap();
+        assert_eq!(
+            loader.flash_md5(0x1000, image.len()).await.unwrap(),
+            format!("{:x}", md5::compute(&image))
+        );
+        // The RTS toggle of the reset isn't supported by ptys, the ROM is done by then.
+        let _ = loader.hard_reset().await;
+
+        let flash = rom.await.unwrap();
+        assert_eq!(&flash[0x1000..0x1000 + image.len()], image);
+        assert!(
+            flash[0x1000 + image.len()..]
+                .iter()
+                .all(|&byte| byte == 0xff)
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! Flash and RAM footprint of a built firmware, read from its ELF file and optional linker map.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSection, SectionKind};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::job::Job;
+use crate::prelude::*;
+use crate::{lvgl_folder, workspace_folder};
+
+/// Extension of the size report written next to the results file.
+pub const REPORT_EXTENSION: &str = "size.toml";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Totals {
+    pub text: u64,
+    pub rodata: u64,
+    pub data: u64,
+    pub bss: u64,
+}
+
+impl Totals {
+    /// Bytes stored in flash: code, constants and the initial value of `.data`
+    pub fn flash(&self) -> u64 {
+        self.text + self.rodata + self.data
+    }
+
+    /// Bytes of RAM used by statically allocated variables
+    pub fn ram(&self) -> u64 {
+        self.data + self.bss
+    }
+
+    fn add(&mut self, kind: Kind, size: u64) {
+        match kind {
+            Kind::Text => self.text += size,
+            Kind::ReadOnlyData => self.rodata += size,
+            Kind::Data => self.data += size,
+            Kind::Bss => self.bss += size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Kind {
+    Text,
+    ReadOnlyData,
+    Data,
+    Bss,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SectionSize {
+    pub name: String,
+    kind: Kind,
+    pub address: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectSize {
+    pub name: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// Difference of the flash and RAM usage against the baseline report, in bytes
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SizeDelta {
+    pub flash: i64,
+    pub ram: i64,
+    pub lvgl_flash: Option<i64>,
+    pub lvgl_ram: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SizeReport {
+    pub elf: PathBuf,
+    pub flash: u64,
+    pub ram: u64,
+    pub totals: Totals,
+    /// LVGL's share of the totals, only known when a linker map is available
+    pub lvgl: Option<Totals>,
+    pub baseline_delta: Option<SizeDelta>,
+    pub sections: Vec<SectionSize>,
+    /// Sizes per static archive, from the linker map
+    pub archives: Vec<ObjectSize>,
+    /// Sizes per LVGL object file, from the linker map
+    pub lvgl_objects: Vec<ObjectSize>,
+}
+
+/// Find the ELF file produced in `build_folder`, for build systems that name it after the project.
+pub fn find_elf(build_folder: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(build_folder)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "elf"))
+}
+
+/// Write the size report of `elf` next to the job results and log how it compares to the baseline.
+/// A missing or unreadable ELF is only a warning so the size report never fails an otherwise
+/// good build.
+pub async fn report(job: &Job, elf: &Path, map: Option<&Path>) -> Result<()> {
+    if !elf.exists() {
+        warn!("No ELF file at {}, skipping size report", elf.display());
+        return Ok(());
+    }
+
+    job.step("firmware size", async {
+        let mut report = match read_elf(elf).await {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("{e}, skipping size report");
+                return Ok(());
+            }
+        };
+
+        if let Some(map) = map.filter(|map| map.exists()) {
+            let contents = tokio::fs::read_to_string(map).await?;
+            let lvgl_folder = std::path::absolute(lvgl_folder(&job.sdk.config_path()))?;
+            let sizes = map_sizes(&contents, &lvgl_folder);
+            report.archives = sizes.archives;
+            report.lvgl_objects = sizes.lvgl_objects;
+            report.lvgl = Some(sizes.lvgl);
+        }
+
+        let report_path = job.results_artifact_path(REPORT_EXTENSION);
+        let baseline_path = match &job.config.firmware_size.baseline {
+            Some(baseline) => workspace_folder(&job.sdk.config_path()).join(baseline),
+            None => report_path.clone(),
+        };
+        if let Some(baseline) = read_report(&baseline_path).await {
+            report.baseline_delta = Some(delta(&report, &baseline));
+        }
+
+        log_report(&report);
+
+        let contents = toml::to_string(&report)?;
+        tokio::fs::write(&report_path, contents).await?;
+
+        Ok(())
+    })
+    .await
+}
+
+/// Read a size report previously written by [`report`].
+pub async fn read_report(path: &Path) -> Option<SizeReport> {
+    let contents = tokio::fs::read_to_string(path).await.ok()?;
+    toml::from_str(&contents).ok()
+}
+
+async fn read_elf(elf: &Path) -> Result<SizeReport> {
+    let data = tokio::fs::read(elf).await?;
+    let file = object::File::parse(&*data)
+        .map_err(|e| Error::FirmwareSize(format!("Failed to parse ELF {}: {e}", elf.display())))?;
+
+    let mut totals = Totals::default();
+    let mut sections = Vec::new();
+
+    for section in file.sections() {
+        let kind = match section.kind() {
+            SectionKind::Text => Kind::Text,
+            SectionKind::ReadOnlyData
+            | SectionKind::ReadOnlyDataWithRel
+            | SectionKind::ReadOnlyString => Kind::ReadOnlyData,
+            SectionKind::Data | SectionKind::Tls => Kind::Data,
+            SectionKind::UninitializedData
+            | SectionKind::UninitializedTls
+            | SectionKind::Common => Kind::Bss,
+            _ => continue,
+        };
+
+        if section.size() == 0 {
+            continue;
+        }
+
+        totals.add(kind, section.size());
+        sections.push(SectionSize {
+            name: section.name().unwrap_or_default().to_string(),
+            kind,
+            address: section.address(),
+            size: section.size(),
+        });
+    }
+
+    Ok(SizeReport {
+        elf: elf.to_path_buf(),
+        flash: totals.flash(),
+        ram: totals.ram(),
+        totals,
+        lvgl: None,
+        baseline_delta: None,
+        sections,
+        archives: Vec::new(),
+        lvgl_objects: Vec::new(),
+    })
+}
+
+/// Sizes attributed from a linker map
+struct MapSizes {
+    archives: Vec<ObjectSize>,
+    lvgl_objects: Vec<ObjectSize>,
+    lvgl: Totals,
+}
+
+/// Attribute the input sections listed in a GNU ld map file to their archives and objects.
+fn map_sizes(contents: &str, lvgl_folder: &Path) -> MapSizes {
+    let mut archives: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut lvgl_objects: BTreeMap<String, Totals> = BTreeMap::new();
+    let mut lvgl = Totals::default();
+
+    for (section, size, path) in map_input_sections(contents) {
+        let Some(kind) = input_section_kind(section) else {
+            continue;
+        };
+
+        let (archive, object) = split_object_path(path);
+        archives
+            .entry(archive.unwrap_or(object).to_string())
+            .or_default()
+            .add(kind, size);
+
+        if is_lvgl_object(path, lvgl_folder) {
+            lvgl.add(kind, size);
+            lvgl_objects
+                .entry(object.to_string())
+                .or_default()
+                .add(kind, size);
+        }
+    }
+
+    MapSizes {
+        archives: into_sorted(archives),
+        lvgl_objects: into_sorted(lvgl_objects),
+        lvgl,
+    }
+}
+
+/// Iterate `(input section, size, object path)` over the memory map part of a linker map.
+/// Long section names make ld wrap the address, size and path onto the next line.
+fn map_input_sections(contents: &str) -> impl Iterator<Item = (&str, u64, &str)> {
+    let memory_map = contents
+        .split_once("Linker script and memory map")
+        .map_or("", |(_, memory_map)| memory_map);
+
+    let mut pending: Option<&str> = None;
+    memory_map.lines().filter_map(move |line| {
+        let (section, rest) = match line.strip_prefix(' ') {
+            Some(rest) if !rest.starts_with(' ') => {
+                let rest = rest.trim_end();
+                match rest.split_once(char::is_whitespace) {
+                    Some((section, rest)) => (section, rest),
+                    None => {
+                        pending = Some(rest);
+                        return None;
+                    }
+                }
+            }
+            _ => (pending.take()?, line),
+        };
+        pending = None;
+
+        if section.starts_with('*') {
+            return None;
+        }
+
+        let mut fields = rest.split_whitespace();
+        fields.next()?.strip_prefix("0x")?;
+        let size = u64::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()?;
+        let path = skip_fields(rest, 2);
+
+        (size > 0 && !path.is_empty()).then_some((section, size, path))
+    })
+}
+
+/// What's left of `text` after its first `count` whitespace separated fields.
+fn skip_fields(text: &str, count: usize) -> &str {
+    (0..count).fold(text.trim_start(), |rest, _| {
+        rest.split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest)
+            .trim_start()
+    })
+}
+
+fn input_section_kind(section: &str) -> Option<Kind> {
+    const KINDS: &[(&str, Kind)] = &[
+        (".text", Kind::Text),
+        (".literal", Kind::Text),
+        (".iram", Kind::Text),
+        (".init", Kind::Text),
+        (".fini", Kind::Text),
+        (".rodata", Kind::ReadOnlyData),
+        (".srodata", Kind::ReadOnlyData),
+        (".data", Kind::Data),
+        (".sdata", Kind::Data),
+        (".dram", Kind::Data),
+        (".bss", Kind::Bss),
+        (".sbss", Kind::Bss),
+        ("COMMON", Kind::Bss),
+    ];
+
+    KINDS
+        .iter()
+        .find(|(prefix, _)| section.starts_with(prefix))
+        .map(|(_, kind)| *kind)
+}
+
+/// Split `path/libfoo.a(bar.c.obj)` into `(Some("libfoo.a"), "bar.c.obj")`.
+fn split_object_path(path: &str) -> (Option<&str>, &str) {
+    match path.strip_suffix(')').and_then(|path| path.split_once('(')) {
+        Some((archive, object)) => (Some(file_name(archive)), object),
+        None => (None, file_name(path)),
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// LVGL ends up in its own archive with ESP-IDF and CMake (`liblvgl.a`, `liblvgl__lvgl.a` for
+/// the managed component, `liblvgl_demos.a`), in `libapps.a` with NuttX and as loose objects
+/// with plain Makefiles. Match on the archive name, on LVGL's `lv_` object file prefix, and on
+/// absolute paths inside `lvgl_folder` for the few LVGL files without the prefix. Board ports
+/// name their glue `lv_port_*` too, those are never LVGL's.
+fn is_lvgl_object(path: &str, lvgl_folder: &Path) -> bool {
+    let (archive, object) = split_object_path(path);
+    let lvgl_archive = archive
+        .and_then(|archive| archive.strip_prefix("lib")?.strip_suffix(".a"))
+        .is_some_and(|name| {
+            name == "lvgl" || name.starts_with("lvgl_") || name.ends_with("__lvgl")
+        });
+    let archive_path = path.split_once('(').map_or(path, |(archive, _)| archive);
+
+    let lvgl_file = object.starts_with("lv_") && !object.starts_with("lv_port");
+
+    lvgl_archive || lvgl_file || Path::new(archive_path).starts_with(lvgl_folder)
+}
+
+fn into_sorted(objects: BTreeMap<String, Totals>) -> Vec<ObjectSize> {
+    let mut objects: Vec<ObjectSize> = objects
+        .into_iter()
+        .map(|(name, totals)| ObjectSize { name, totals })
+        .collect();
+    objects.sort_by_key(|object| std::cmp::Reverse(object.totals.flash() + object.totals.bss));
+    objects
+}
+
+fn delta(report: &SizeReport, baseline: &SizeReport) -> SizeDelta {
+    let diff = |new: u64, old: u64| new as i64 - old as i64;
+    let lvgl = report.lvgl.zip(baseline.lvgl);
+
+    SizeDelta {
+        flash: diff(report.flash, baseline.flash),
+        ram: diff(report.ram, baseline.ram),
+        lvgl_flash: lvgl.map(|(new, old)| diff(new.flash(), old.flash())),
+        lvgl_ram: lvgl.map(|(new, old)| diff(new.ram(), old.ram())),
+    }
+}
+
+fn log_report(report: &SizeReport) {
+    info!(
+        "Firmware size: flash={} ram={} (text={} rodata={} data={} bss={})",
+        report.flash,
+        report.ram,
+        report.totals.text,
+        report.totals.rodata,
+        report.totals.data,
+        report.totals.bss
+    );
+
+    if let Some(lvgl) = report.lvgl {
+        info!("LVGL size: flash={} ram={}", lvgl.flash(), lvgl.ram());
+    }
+
+    if let Some(delta) = report.baseline_delta {
+        info!(
+            "Size change against baseline: flash={:+} ram={:+} lvgl_flash={} lvgl_ram={}",
+            delta.flash,
+            delta.ram,
+            delta
+                .lvgl_flash
+                .map_or("n/a".to_string(), |d| format!("{d:+}")),
+            delta
+                .lvgl_ram
+                .map_or("n/a".to_string(), |d| format!("{d:+}")),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ESP_IDF_MAP: &str = include_str!("../testdata/firmware_size/esp-idf.map");
+    const GNU_LD_MAP: &str = include_str!("../testdata/firmware_size/gnu-ld.map");
+    const LVGL_FOLDER: &str = "/work/lvgl-ci/lvgl";
+
+    fn names(objects: &[ObjectSize]) -> Vec<&str> {
+        objects.iter().map(|object| object.name.as_str()).collect()
+    }
+
+    #[test]
+    fn wrapped_input_sections() {
+        let sections: Vec<_> = map_input_sections(GNU_LD_MAP).collect();
+        assert_eq!(
+            sections,
+            vec![
+                (".isr_vector", 0x1ac, "build/startup_stm32h743xx.o"),
+                (".text.main", 0x48, "/work/lvgl-ci/stm32/build/main.o"),
+                (
+                    ".text.lv_draw_sw_blend_color_to_rgb565_with_opa",
+                    0x2f0,
+                    "build/lv_draw_sw_blend_to_rgb565.o"
+                ),
+                (
+                    ".text.stbtt_GetGlyphShape",
+                    0x120,
+                    "/work/lvgl-ci/lvgl/build/stb_truetype.o"
+                ),
+                (".text.lv_port_disp_init", 0x60, "build/lv_port_disp.o"),
+                (
+                    ".rodata.lv_font_montserrat_14",
+                    0x400,
+                    "build/lv_font_montserrat_14.o"
+                ),
+                (
+                    ".data.SystemCoreClock",
+                    0x4,
+                    "/work/lvgl-ci/stm32/build/system_stm32h7xx.o"
+                ),
+                (".bss.lv_global", 0x200, "build/lv_global.o"),
+                ("COMMON", 0x10, "/work/lvgl-ci/stm32/build/main.o"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gnu_ld_map() {
+        let sizes = map_sizes(GNU_LD_MAP, Path::new(LVGL_FOLDER));
+        assert_eq!(
+            sizes.lvgl,
+            Totals {
+                text: 0x2f0 + 0x120,
+                rodata: 0x400,
+                data: 0,
+                bss: 0x200,
+            }
+        );
+        assert_eq!(
+            names(&sizes.lvgl_objects),
+            vec![
+                "lv_font_montserrat_14.o",
+                "lv_draw_sw_blend_to_rgb565.o",
+                "lv_global.o",
+                "stb_truetype.o",
+            ]
+        );
+    }
+
+    #[test]
+    fn esp_idf_map() {
+        let sizes = map_sizes(ESP_IDF_MAP, Path::new(LVGL_FOLDER));
+        assert_eq!(
+            sizes.lvgl,
+            Totals {
+                text: 0x3c + 0x10,
+                rodata: 0x80,
+                data: 0,
+                bss: 0x100,
+            }
+        );
+        assert_eq!(
+            names(&sizes.lvgl_objects),
+            vec!["lv_global.c.obj", "lv_obj.c.obj", "lv_obj_class.c.obj"]
+        );
+        assert_eq!(
+            names(&sizes.archives),
+            vec![
+                "liblvgl__lvgl.a",
+                "libespressif__esp_lvgl_port.a",
+                "libmain.a",
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Flashing with retries, a baud rate fallback and verification, per the board's `flash` settings.
+
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::config::FlashConfig;
+use crate::job::Job;
+use crate::prelude::*;
+
+/// Run `write` until it succeeds, followed by `verify` when enabled, as the step `name`.
+/// Both get the baud rate of the attempt. Every attempt is recorded as a step of its own.
+pub async fn flash<W, V>(
+    job: &Job,
+    name: &str,
+    default_baud: u32,
+    write: impl Fn(u32) -> W,
+    verify: impl Fn(u32) -> V,
+) -> Result<()>
+where
+    W: Future<Output = Result<()>>,
+    V: Future<Output = Result<()>>,
+{
+    let config = &job.settings.flash;
+    let (write, verify) = (&write, &verify);
+    with_retries(config, name, default_baud, |step_name, baud| async move {
+        job.step(&step_name, write(baud)).await?;
+        if config.verify {
+            job.step(&format!("{step_name} verify"), verify(baud))
+                .await?;
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Run `attempt` with the step name and baud rate of every attempt until it succeeds, fails
+/// with an error that isn't worth retrying, or `config.attempts` are used up.
+async fn with_retries<A>(
+    config: &FlashConfig,
+    name: &str,
+    default_baud: u32,
+    attempt: impl Fn(String, u32) -> A,
+) -> Result<()>
+where
+    A: Future<Output = Result<()>>,
+{
+    let attempts = config.attempts.max(1);
+    let mut failures = Vec::new();
+
+    for number in 1..=attempts {
+        let baud = attempt_baud(config, number, default_baud);
+        let step_name = if number == 1 {
+            name.to_string()
+        } else {
+            let backoff = config
+                .backoff_secs
+                .saturating_mul(1 << (number - 2).min(16));
+            info!("Retrying {name} in {backoff}s");
+            tokio::time::sleep(Duration::from_secs(backoff)).await;
+            format!("{name} (attempt {number}/{attempts})")
+        };
+
+        info!("{name}: attempt {number}/{attempts} at {baud} baud");
+        let message = match attempt(step_name, baud).await {
+            Ok(()) => return Ok(()),
+            // Only the tool or the board failing is worth retrying, not e.g. the tool missing.
+            // A board re-enumerating after a reset can briefly refuse its port. A port that is
+            // gone isn't retried, it was looked up once before flashing.
+            Err(Error::CommandFailed(message) | Error::EspLoader(message)) => message,
+            Err(Error::SerialPort(e)) => e.to_string(),
+            Err(e) => return Err(e),
+        };
+        warn!("{name}: attempt {number}/{attempts} at {baud} baud failed: {message}");
+        failures.push(format!("#{number} at {baud} baud: {message}"));
+    }
+
+    Err(Error::FlashFailed(format!(
+        "{name} failed {attempts} times: {}",
+        failures.join("; ")
+    )))
+}
+
+/// Baud rate of attempt `number`: the configured or tool default one first, then the fallback.
+fn attempt_baud(config: &FlashConfig, number: u32, default_baud: u32) -> u32 {
+    match (number, config.fallback_baud) {
+        (2.., Some(fallback)) => fallback,
+        _ => config.baud.unwrap_or(default_baud),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn config(toml: &str) -> FlashConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    /// Run `with_retries` against attempts returning `results` in order, recording the step
+    /// name and baud rate of every attempt.
+    async fn run(
+        config: &FlashConfig,
+        results: Vec<Result<()>>,
+    ) -> (Result<()>, Vec<(String, u32)>) {
+        let results = Mutex::new(results.into_iter());
+        let calls = Mutex::new(Vec::new());
+        let result = with_retries(config, "write_flash", 460_800, |step_name, baud| {
+            calls.lock().unwrap().push((step_name, baud));
+            let result = results.lock().unwrap().next().expect("an attempt too many");
+            async move { result }
+        })
+        .await;
+        (result, calls.into_inner().unwrap())
+    }
+
+    #[tokio::test]
+    async fn retries_until_success_at_the_fallback_baud() {
+        let config =
+            config("attempts = 4\nbackoff_secs = 0\nbaud = 921600\nfallback_baud = 115200");
+        let (result, calls) = run(
+            &config,
+            vec![
+                Err(Error::CommandFailed("esptool.py exited with 2".to_string())),
+                Err(Error::EspLoader(
+                    "Timeout waiting for a response".to_string(),
+                )),
+                Ok(()),
+            ],
+        )
+        .await;
+
+        result.unwrap();
+        assert_eq!(
+            calls,
+            vec![
+                ("write_flash".to_string(), 921_600),
+                ("write_flash (attempt 2/4)".to_string(), 115_200),
+                ("write_flash (attempt 3/4)".to_string(), 115_200),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_baud_without_fallback() {
+        let config = config("attempts = 2\nbackoff_secs = 0");
+        let (result, calls) = run(
+            &config,
+            vec![
+                Err(Error::CommandFailed("exited with 2".to_string())),
+                Err(Error::CommandFailed("exited with 1".to_string())),
+            ],
+        )
+        .await;
+
+        match result {
+            Err(Error::FlashFailed(message)) => assert_eq!(
+                message,
+                "write_flash failed 2 times: #1 at 460800 baud: exited with 2; \
+                 #2 at 460800 baud: exited with 1"
+            ),
+            other => panic!("expected FlashFailed, got {other:?}"),
+        }
+        assert_eq!(calls.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_on_errors_not_worth_retrying() {
+        let config = config("attempts = 3\nbackoff_secs = 0");
+        let (result, calls) = run(
+            &config,
+            vec![Err(Error::DeviceNotFound(
+                "ESP32-S3 at /dev/ttyACM0".to_string(),
+            ))],
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::DeviceNotFound(_))));
+        assert_eq!(calls.len(), 1);
+    }
+}
@@ -0,0 +1,20 @@
+//! Golden-file assertions for generated files.
+
+use std::path::Path;
+
+/// Compare `actual` with `testdata/<name>`, or rewrite it when `UPDATE_GOLDEN` is set.
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(name);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&path, actual).unwrap();
+    }
+    let expected = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(
+        actual,
+        expected,
+        "{} differs, run with UPDATE_GOLDEN=1 to update it",
+        path.display()
+    );
+}
@@ -0,0 +1,208 @@
+//! Per-job context handed to the board backends.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ej_builder_sdk::BuilderSdk;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+use crate::config::{BoardConfigSettings, EjLvBuilderConfig};
+use crate::diagnostics::{self, Diagnostic};
+use crate::prelude::*;
+use crate::{results_artifact_path, results_path, slug};
+
+/// Outcome of a single build or run step.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    pub duration: Duration,
+    /// Why the step failed, `None` if it succeeded
+    pub failure: Option<String>,
+}
+
+pub struct Job {
+    pub sdk: BuilderSdk,
+    pub config: EjLvBuilderConfig,
+    /// Settings of this job's board config
+    pub settings: BoardConfigSettings,
+    /// Results file name for this board with `results.layout = "legacy"`
+    legacy_results_name: &'static str,
+    steps: Mutex<Vec<Step>>,
+    /// Compiler diagnostics seen in the output of the commands, deduplicated by file and line
+    diagnostics: Mutex<BTreeMap<(String, u32), Diagnostic>>,
+    /// Raw output captured from the application, if it got to run
+    output: Mutex<Option<String>>,
+}
+
+impl Job {
+    pub async fn load(
+        sdk: BuilderSdk,
+        config: EjLvBuilderConfig,
+        legacy_results_name: &'static str,
+    ) -> Result<Self> {
+        let settings = config.board_config(sdk.board_name(), sdk.board_config_name());
+
+        let job = Self {
+            sdk,
+            config,
+            settings,
+            legacy_results_name,
+            steps: Mutex::new(Vec::new()),
+            diagnostics: Mutex::new(BTreeMap::new()),
+            output: Mutex::new(None),
+        };
+
+        // Build and run jobs both write next to the results, which may live in a subfolder.
+        if let Some(parent) = job.results_path().parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        Ok(job)
+    }
+
+    /// Normalized `<board>-<board config>` name identifying this job's outputs.
+    pub fn slug(&self) -> String {
+        format!(
+            "{}-{}",
+            slug(self.sdk.board_name()),
+            slug(self.sdk.board_config_name())
+        )
+    }
+
+    pub fn results_path(&self) -> PathBuf {
+        results_path(
+            &self.sdk.config_path(),
+            self.config.results.layout,
+            self.sdk.board_name(),
+            self.sdk.board_config_name(),
+            self.legacy_results_name,
+        )
+    }
+
+    /// Path of an extra result file stored next to the results, e.g. `results-esp32s3-eve.size.toml`.
+    pub fn results_artifact_path(&self, extension: &str) -> PathBuf {
+        results_artifact_path(
+            &self.sdk.config_path(),
+            self.config.results.layout,
+            self.sdk.board_name(),
+            self.sdk.board_config_name(),
+            extension,
+        )
+    }
+
+    /// Await `step`, recording its duration and outcome under `name`.
+    pub async fn step<T>(&self, name: &str, step: impl Future<Output = Result<T>>) -> Result<T> {
+        let started = Instant::now();
+        let result = step.await;
+        self.record_step(
+            name,
+            started.elapsed(),
+            result.as_ref().err().map(ToString::to_string),
+        );
+        result
+    }
+
+    /// Run `command` to completion as the step `name`.
+    /// A non-zero exit status is turned into [`Error::CommandFailed`].
+    pub async fn run_step(&self, name: &str, command: &mut Command) -> Result<()> {
+        self.step(name, self.run_checked(name, command)).await
+    }
+
+    /// Run `command` to completion, turning a non-zero exit status into [`Error::CommandFailed`].
+    pub async fn run_checked(&self, name: &str, command: &mut Command) -> Result<()> {
+        let status = self.run_command(command).await?;
+        if !status.success() {
+            return Err(Error::CommandFailed(format!("{name} exited with {status}")));
+        }
+        Ok(())
+    }
+
+    /// Run `command` to completion, echoing its output while scanning it for compiler diagnostics.
+    pub async fn run_command(&self, command: &mut Command) -> Result<ExitStatus> {
+        let mut child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Compilers print paths relative to the folder they run in.
+        let dir = match command.as_std().get_current_dir() {
+            Some(dir) => std::path::absolute(dir)?,
+            None => std::env::current_dir()?,
+        };
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let (stdout, stderr) = tokio::join!(
+            self.scan_output(stdout, tokio::io::stdout(), &dir),
+            self.scan_output(stderr, tokio::io::stderr(), &dir),
+        );
+        stdout?;
+        stderr?;
+
+        Ok(child.wait().await?)
+    }
+
+    async fn scan_output(
+        &self,
+        output: impl AsyncRead + Unpin,
+        mut echo: impl AsyncWrite + Unpin,
+        dir: &Path,
+    ) -> Result<()> {
+        let mut reader = BufReader::new(output);
+        let mut line = Vec::new();
+
+        while reader.read_until(b'\n', &mut line).await? > 0 {
+            echo.write_all(&line).await?;
+            echo.flush().await?;
+
+            if let Some(diagnostic) = diagnostics::parse_line(&String::from_utf8_lossy(&line)) {
+                self.record_diagnostic(diagnostic.resolve(dir));
+            }
+            line.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn record_diagnostic(&self, diagnostic: Diagnostic) {
+        let mut diagnostics = self.diagnostics.lock().unwrap();
+        let key = (diagnostic.file.clone(), diagnostic.line);
+        match diagnostics.get(&key) {
+            // The same line reported as both a warning and an error counts as an error.
+            Some(existing) if existing.severity >= diagnostic.severity => {}
+            _ => {
+                diagnostics.insert(key, diagnostic);
+            }
+        }
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Record a step that was run outside of [`Job::step`].
+    pub fn record_step(&self, name: &str, duration: Duration, failure: Option<String>) {
+        self.steps.lock().unwrap().push(Step {
+            name: name.to_string(),
+            duration,
+            failure,
+        });
+    }
+
+    pub fn steps(&self) -> Vec<Step> {
+        self.steps.lock().unwrap().clone()
+    }
+
+    /// Keep the raw application output so it can be attached to the job reports.
+    pub fn record_output(&self, output: &str) {
+        *self.output.lock().unwrap() = Some(output.to_string());
+    }
+
+    pub fn output(&self) -> Option<String> {
+        self.output.lock().unwrap().clone()
+    }
+}
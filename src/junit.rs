@@ -0,0 +1,395 @@
+//! JUnit XML report of a job so CI dashboards can show build and benchmark outcomes.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+use ej_builder_sdk::Action;
+use tracing::info;
+
+use crate::benchmark::{SceneResult, parse_scenes};
+use crate::job::Job;
+use crate::prelude::*;
+use crate::workspace_folder;
+
+struct TestCase {
+    classname: String,
+    name: String,
+    duration: Duration,
+    outcome: Outcome,
+    system_out: Option<String>,
+}
+
+enum Outcome {
+    Passed,
+    Skipped,
+    Failed {
+        kind: &'static str,
+        message: String,
+        details: String,
+    },
+}
+
+/// Read the results of the previous run so scenes can be checked for regressions.
+/// Returns `None` when regression checks are disabled or there are no previous results.
+pub async fn read_baseline(job: &Job) -> Option<Vec<SceneResult>> {
+    job.config.junit.dir.as_ref()?;
+    job.config.junit.fps_regression_threshold?;
+
+    let previous = tokio::fs::read_to_string(job.results_path()).await.ok()?;
+    Some(parse_scenes(&previous))
+}
+
+/// Write the JUnit report for `job`, if enabled in the config.
+pub async fn write_report(
+    job: &Job,
+    result: &Result<()>,
+    baseline: Option<&[SceneResult]>,
+) -> Result<()> {
+    let Some(dir) = &job.config.junit.dir else {
+        return Ok(());
+    };
+
+    let action: &str = job.sdk.action().into();
+    let suite_name = format!(
+        "{}.{}.{}",
+        job.sdk.board_name(),
+        job.sdk.board_config_name(),
+        action
+    );
+
+    let steps = job.steps();
+    let mut cases: Vec<TestCase> = steps
+        .iter()
+        .map(|step| TestCase {
+            classname: suite_name.clone(),
+            name: step.name.clone(),
+            duration: step.duration,
+            outcome: match &step.failure {
+                Some(failure) => Outcome::Failed {
+                    kind: "step",
+                    message: summary(failure),
+                    details: failure.clone(),
+                },
+                None => Outcome::Passed,
+            },
+            system_out: None,
+        })
+        .collect();
+
+    let output = job.output();
+    if let Action::Run = job.sdk.action() {
+        cases.extend(benchmark_cases(job, output.as_deref(), result, baseline));
+    }
+
+    // Errors raised outside of a recorded step would otherwise go unnoticed.
+    if let Err(err) = result
+        && !steps.iter().any(|step| step.failure.is_some())
+    {
+        cases.push(TestCase {
+            classname: suite_name.clone(),
+            name: action.to_string(),
+            duration: Duration::ZERO,
+            outcome: Outcome::Failed {
+                kind: failure_kind(err),
+                message: summary(&err.to_string()),
+                details: err.to_string(),
+            },
+            system_out: None,
+        });
+    }
+
+    let xml = render(&suite_name, &cases, output.as_deref());
+
+    let dir = workspace_folder(&job.sdk.config_path()).join(dir);
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}-{}.xml", job.slug(), action));
+    tokio::fs::write(&path, xml).await?;
+    info!("JUnit report written to {}", path.display());
+
+    Ok(())
+}
+
+fn benchmark_cases(
+    job: &Job,
+    output: Option<&str>,
+    result: &Result<()>,
+    baseline: Option<&[SceneResult]>,
+) -> Vec<TestCase> {
+    let classname = format!(
+        "{}.{}.benchmark",
+        job.sdk.board_name(),
+        job.sdk.board_config_name()
+    );
+
+    let Some(output) = output else {
+        // Boards with runs disabled finish without producing any output.
+        if result.is_ok() {
+            return vec![TestCase {
+                classname,
+                name: "benchmark".to_string(),
+                duration: Duration::ZERO,
+                outcome: Outcome::Skipped,
+                system_out: None,
+            }];
+        }
+        return Vec::new();
+    };
+
+    let scenes = parse_scenes(output);
+    if scenes.is_empty() && result.is_ok() {
+        return vec![TestCase {
+            classname,
+            name: "benchmark".to_string(),
+            duration: Duration::ZERO,
+            outcome: Outcome::Failed {
+                kind: "parse",
+                message: "No benchmark results found in the output".to_string(),
+                details: String::new(),
+            },
+            system_out: None,
+        }];
+    }
+
+    let threshold = job.config.junit.fps_regression_threshold;
+
+    scenes
+        .into_iter()
+        .map(|scene| {
+            let outcome = scene_outcome(&scene, baseline, threshold);
+            TestCase {
+                classname: classname.clone(),
+                duration: Duration::ZERO,
+                system_out: Some(format!(
+                    "cpu={}% fps={} avg_time={}ms render_time={}ms flush_time={}ms",
+                    scene.cpu, scene.fps, scene.avg_time, scene.render_time, scene.flush_time
+                )),
+                name: scene.name,
+                outcome,
+            }
+        })
+        .collect()
+}
+
+/// Fail `scene` when its FPS dropped more than `threshold` percent below the same scene of the
+/// baseline.
+fn scene_outcome(
+    scene: &SceneResult,
+    baseline: Option<&[SceneResult]>,
+    threshold: Option<f64>,
+) -> Outcome {
+    let previous =
+        baseline.and_then(|baseline| baseline.iter().find(|prev| prev.name == scene.name));
+
+    match (previous, threshold) {
+        (Some(previous), Some(threshold))
+            if (scene.fps as f64) < previous.fps as f64 * (1.0 - threshold / 100.0) =>
+        {
+            Outcome::Failed {
+                kind: "regression",
+                message: format!(
+                    "FPS dropped from {} to {} (threshold {threshold}%)",
+                    previous.fps, scene.fps
+                ),
+                details: String::new(),
+            }
+        }
+        _ => Outcome::Passed,
+    }
+}
+
+fn failure_kind(err: &Error) -> &'static str {
+    match err {
+        Error::TimeoutWaitingForBenchmarkToEnd(_) => "timeout",
+        Error::DeviceCrashed(..) => "crash",
+        Error::CommandFailed(_) => "command",
+        _ => "error",
+    }
+}
+
+/// First line of a possibly long error message, suitable for the `message` attribute.
+fn summary(message: &str) -> String {
+    let line = message.lines().next().unwrap_or_default();
+    line.chars().take(200).collect()
+}
+
+fn render(suite_name: &str, cases: &[TestCase], system_out: Option<&str>) -> String {
+    let failures = cases
+        .iter()
+        .filter(|case| matches!(case.outcome, Outcome::Failed { .. }))
+        .count();
+    let skipped = cases
+        .iter()
+        .filter(|case| matches!(case.outcome, Outcome::Skipped))
+        .count();
+    let time: f64 = cases.iter().map(|case| case.duration.as_secs_f64()).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuites name=\"ejlv_builder\" tests=\"{}\" failures=\"{failures}\" errors=\"0\" skipped=\"{skipped}\" time=\"{time:.3}\">",
+        cases.len()
+    );
+    let _ = writeln!(
+        xml,
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" errors=\"0\" skipped=\"{skipped}\" time=\"{time:.3}\">",
+        escape(suite_name),
+        cases.len()
+    );
+
+    for case in cases {
+        let _ = write!(
+            xml,
+            "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+            escape(&case.classname),
+            escape(&case.name),
+            case.duration.as_secs_f64()
+        );
+
+        if matches!(case.outcome, Outcome::Passed) && case.system_out.is_none() {
+            xml.push_str("/>\n");
+            continue;
+        }
+        xml.push_str(">\n");
+
+        match &case.outcome {
+            Outcome::Passed => {}
+            Outcome::Skipped => xml.push_str("      <skipped/>\n"),
+            Outcome::Failed {
+                kind,
+                message,
+                details,
+            } => {
+                let _ = writeln!(
+                    xml,
+                    "      <failure type=\"{kind}\" message=\"{}\">{}</failure>",
+                    escape(message),
+                    escape(details)
+                );
+            }
+        }
+        if let Some(out) = &case.system_out {
+            let _ = writeln!(xml, "      <system-out>{}</system-out>", escape(out));
+        }
+        xml.push_str("    </testcase>\n");
+    }
+
+    if let Some(out) = system_out {
+        let _ = writeln!(xml, "    <system-out>{}</system-out>", escape(out));
+    }
+
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// Escape `text` for use in XML content and attributes.
+/// Control characters that XML 1.0 can't represent (serial line noise) are replaced.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINE: &str = "\
+I (312) main_task: Calling app_main()
+Benchmark Start
+Name,Avg. CPU,Avg. FPS,Avg. time,render time,flush time
+Empty screen,11%,30,1,1,0
+Moving wallpaper,45%,28,12,10,2
+Rotated ARGB image, 90 deg,70%,20,30,25,5
+Benchmark Over
+";
+
+    fn scene(name: &str, fps: u32) -> SceneResult {
+        SceneResult {
+            name: name.to_string(),
+            cpu: 50,
+            fps,
+            avg_time: 10,
+            render_time: 8,
+            flush_time: 2,
+        }
+    }
+
+    fn failure(outcome: Outcome) -> Option<String> {
+        match outcome {
+            Outcome::Failed { kind, message, .. } => Some(format!("{kind}: {message}")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn escape_markup_and_control_characters() {
+        assert_eq!(
+            escape("<a href=\"x\">'R&D'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&apos;R&amp;D&apos;&lt;/a&gt;"
+        );
+        assert_eq!(escape("line\r\n\tend"), "line\r\n\tend");
+        assert_eq!(escape("noise\x00\x1b[0m"), "noise\u{fffd}\u{fffd}[0m");
+    }
+
+    #[test]
+    fn baseline_scenes() {
+        let baseline = parse_scenes(BASELINE);
+        let names: Vec<_> = baseline.iter().map(|scene| scene.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "Empty screen",
+                "Moving wallpaper",
+                "Rotated ARGB image, 90 deg"
+            ]
+        );
+        assert_eq!(baseline[2].fps, 20);
+    }
+
+    #[test]
+    fn fps_regression_threshold() {
+        let baseline = parse_scenes(BASELINE);
+        let baseline = Some(baseline.as_slice());
+
+        // 10% below 30 FPS is 27 FPS, which is still fine.
+        assert_eq!(
+            failure(scene_outcome(
+                &scene("Empty screen", 27),
+                baseline,
+                Some(10.0)
+            )),
+            None
+        );
+        assert_eq!(
+            failure(scene_outcome(
+                &scene("Empty screen", 26),
+                baseline,
+                Some(10.0)
+            )),
+            Some("regression: FPS dropped from 30 to 26 (threshold 10%)".to_string())
+        );
+        assert_eq!(
+            failure(scene_outcome(&scene("Empty screen", 26), baseline, None)),
+            None
+        );
+        assert_eq!(
+            failure(scene_outcome(&scene("New scene", 1), baseline, Some(10.0))),
+            None
+        );
+        assert_eq!(
+            failure(scene_outcome(&scene("Empty screen", 1), None, Some(10.0))),
+            None
+        );
+    }
+}
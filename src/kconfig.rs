@@ -0,0 +1,359 @@
+//! Editing of Kconfig output files: NuttX `.config` and ESP-IDF `sdkconfig`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use tracing::warn;
+
+use crate::config::KconfigValue;
+
+/// What LVGL's Kconfig declares about its symbols, enough to turn lv_conf.h values into
+/// Kconfig settings.
+#[derive(Debug, Default)]
+pub struct Symbols {
+    bools: BTreeSet<String>,
+    /// Entries of every `choice` block
+    choices: Vec<Vec<String>>,
+    /// Promptless symbols set by `default <value> if <choice entry>`, such as
+    /// `LV_USE_DRAW_SW_ASM`, with their `(value, entry)` pairs
+    derived: BTreeMap<String, Vec<(String, String)>>,
+}
+
+impl Symbols {
+    /// Parse the `config`, `choice` and `default ... if` lines of a Kconfig file. Nothing is
+    /// evaluated, sourced files and help texts are ignored.
+    pub fn parse(kconfig: &str) -> Self {
+        let mut symbols = Symbols::default();
+        let mut choice: Option<Vec<String>> = None;
+        // Symbol being declared, and whether it has a prompt
+        let mut current: Option<(String, bool)> = None;
+        let mut help_indent: Option<usize> = None;
+
+        for line in kconfig.lines() {
+            let trimmed = line.trim();
+            let indent = indentation(line);
+            if let Some(help) = help_indent {
+                if trimmed.is_empty() || indent > help {
+                    continue;
+                }
+                help_indent = None;
+            }
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+
+            let (keyword, rest) = trimmed
+                .split_once(char::is_whitespace)
+                .unwrap_or((trimmed, ""));
+            let rest = rest.trim();
+            match keyword {
+                "config" | "menuconfig" => current = Some((rest.to_string(), false)),
+                "choice" => {
+                    choice = Some(Vec::new());
+                    current = None;
+                }
+                "endchoice" => {
+                    symbols.choices.extend(choice.take());
+                    current = None;
+                }
+                "bool" | "tristate" | "def_bool" | "def_tristate" => {
+                    if let Some((symbol, prompted)) = &mut current {
+                        *prompted |= keyword.starts_with("def_") || !rest.is_empty();
+                        symbols.bools.insert(symbol.clone());
+                        if let Some(entries) = &mut choice {
+                            entries.push(symbol.clone());
+                        }
+                    }
+                }
+                "int" | "hex" | "string" | "def_int" | "def_hex" | "def_string" => {
+                    if let Some((_, prompted)) = &mut current {
+                        *prompted |= keyword.starts_with("def_") || !rest.is_empty();
+                    }
+                }
+                "prompt" => {
+                    if let Some((_, prompted)) = &mut current {
+                        *prompted = true;
+                    }
+                }
+                "default" => {
+                    if let Some((symbol, false)) = &current
+                        && let Some((value, condition)) = rest.split_once(" if ")
+                    {
+                        symbols
+                            .derived
+                            .entry(symbol.clone())
+                            .or_default()
+                            .push((value.trim().to_string(), condition.trim().to_string()));
+                    }
+                }
+                "help" | "---help---" => help_indent = Some(indent),
+                "menu" | "endmenu" | "if" | "endif" | "comment" | "source" | "rsource"
+                | "osource" | "orsource" | "mainmenu" => current = None,
+                _ => {}
+            }
+        }
+
+        symbols
+    }
+
+    /// Kconfig settings for lv_conf.h style `options`, without `CONFIG_` prefixes. `0`/`1`
+    /// become booleans for bool symbols, and a value of a symbol derived from a choice selects
+    /// the choice entry giving that value. Anything else is passed through as is.
+    pub fn translate(
+        &self,
+        options: &BTreeMap<String, KconfigValue>,
+    ) -> BTreeMap<String, KconfigValue> {
+        let mut settings = BTreeMap::new();
+        for (symbol, value) in unprefixed(options) {
+            if let Some(entry) = self.choice_entry(&symbol, &value) {
+                for sibling in self
+                    .choices
+                    .iter()
+                    .filter(|c| c.iter().any(|e| e == entry))
+                    .flatten()
+                {
+                    settings
+                        .entry(sibling.clone())
+                        .or_insert(KconfigValue::Bool(false));
+                }
+                settings.insert(entry.to_string(), KconfigValue::Bool(true));
+                continue;
+            }
+            let value = match value {
+                KconfigValue::Integer(n @ (0 | 1)) if self.bools.contains(&symbol) => {
+                    KconfigValue::Bool(n == 1)
+                }
+                value => value,
+            };
+            settings.insert(symbol, value);
+        }
+        settings
+    }
+
+    fn choice_entry(&self, symbol: &str, value: &KconfigValue) -> Option<&str> {
+        let value = match value {
+            KconfigValue::Integer(n) => n.to_string(),
+            KconfigValue::String(s) => s.clone(),
+            KconfigValue::Bool(_) => return None,
+        };
+        self.derived
+            .get(symbol)?
+            .iter()
+            .find(|(v, entry)| *v == value && self.choices.iter().any(|c| c.contains(entry)))
+            .map(|(_, entry)| entry.as_str())
+    }
+}
+
+fn indentation(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| c.is_whitespace())
+        .map(|c| if c == '\t' { 8 } else { 1 })
+        .sum()
+}
+
+/// `options` with the `CONFIG_` prefix stripped from their names, so that both spellings of an
+/// option end up as one entry.
+pub fn unprefixed(options: &BTreeMap<String, KconfigValue>) -> BTreeMap<String, KconfigValue> {
+    options
+        .iter()
+        .map(|(name, value)| {
+            let symbol = name.strip_prefix("CONFIG_").unwrap_or(name);
+            (symbol.to_string(), value.clone())
+        })
+        .collect()
+}
+
+/// Settings of `requested` that `config` doesn't have, as `wanted` and `found` lines. Kconfig
+/// drops values whose dependencies aren't met or that are out of range when resolving the
+/// configuration. A disabled bool may also be left out entirely.
+pub fn dropped(
+    config: &str,
+    requested: &BTreeMap<String, KconfigValue>,
+) -> Vec<(String, Option<String>)> {
+    let lines: BTreeMap<&str, &str> = config
+        .lines()
+        .filter_map(|line| config_symbol(line).map(|symbol| (symbol, line)))
+        .collect();
+    unprefixed(requested)
+        .iter()
+        .filter_map(|(symbol, value)| {
+            let wanted = config_line(symbol, value);
+            match lines.get(symbol.as_str()) {
+                Some(line) if *line == wanted => None,
+                None if *value == KconfigValue::Bool(false) => None,
+                found => Some((wanted, found.map(|line| line.to_string()))),
+            }
+        })
+        .collect()
+}
+
+/// Set `overrides` in `config`, replacing the existing entries in place and appending the
+/// options it doesn't mention yet. Option names may be given with or without `CONFIG_`.
+pub fn apply(config: &str, overrides: &BTreeMap<String, KconfigValue>) -> String {
+    let overrides: BTreeMap<&str, &KconfigValue> = overrides
+        .iter()
+        .map(|(name, value)| (name.strip_prefix("CONFIG_").unwrap_or(name), value))
+        .collect();
+    let mut applied = BTreeSet::new();
+    let mut result = String::with_capacity(config.len());
+
+    for line in config.lines() {
+        match config_symbol(line).and_then(|symbol| overrides.get_key_value(symbol)) {
+            Some((symbol, value)) => {
+                result.push_str(&config_line(symbol, value));
+                applied.insert(*symbol);
+            }
+            None => result.push_str(line),
+        }
+        result.push('\n');
+    }
+
+    for (symbol, value) in &overrides {
+        if !applied.contains(symbol) {
+            result.push_str(&config_line(symbol, value));
+            result.push('\n');
+        }
+    }
+
+    result
+}
+
+/// Symbol set by a `CONFIG_FOO=...` or `# CONFIG_FOO is not set` line, without its prefix.
+fn config_symbol(line: &str) -> Option<&str> {
+    if let Some(unset) = line.strip_prefix("# CONFIG_") {
+        return unset.strip_suffix(" is not set");
+    }
+    line.strip_prefix("CONFIG_")?
+        .split_once('=')
+        .map(|(symbol, _)| symbol)
+}
+
+fn config_line(symbol: &str, value: &KconfigValue) -> String {
+    match value {
+        KconfigValue::Bool(true) => format!("CONFIG_{symbol}=y"),
+        KconfigValue::Bool(false) => format!("# CONFIG_{symbol} is not set"),
+        KconfigValue::Integer(value) => format!("CONFIG_{symbol}={value}"),
+        KconfigValue::String(value) if is_hex(value) => format!("CONFIG_{symbol}={value}"),
+        KconfigValue::String(value) => format!(
+            "CONFIG_{symbol}=\"{}\"",
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+    }
+}
+
+fn is_hex(value: &str) -> bool {
+    value
+        .strip_prefix("0x")
+        .is_some_and(|digits| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Warn about the settings of `requested` missing from the resolved `config`.
+pub fn warn_dropped(config: &str, requested: &BTreeMap<String, KconfigValue>) {
+    for (wanted, found) in dropped(config, requested) {
+        match found {
+            Some(found) => warn!("Kconfig replaced `{wanted}` with `{found}`"),
+            None => warn!("Kconfig dropped `{wanted}`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::assert_golden;
+
+    const DOT_CONFIG: &str = include_str!("../testdata/kconfig/dot-config");
+    const LVGL_KCONFIG: &str = include_str!("../testdata/kconfig/lvgl.Kconfig");
+
+    fn options(toml: &str) -> BTreeMap<String, KconfigValue> {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn overrides() {
+        let overrides = options(
+            r#"
+            LV_USE_DEMO_BENCHMARK = true
+            CONFIG_LV_USE_LOG = false
+            LV_MEM_SIZE_KILOBYTES = 256
+            LV_OPTLEVEL = "-O3"
+            LV_ATTRIBUTE_MEM_ALIGN_SIZE = "0x4"
+            LV_USE_PERF_MONITOR = true
+            "#,
+        );
+        assert_golden("kconfig/dot-config.golden", &apply(DOT_CONFIG, &overrides));
+    }
+
+    #[test]
+    fn no_overrides() {
+        assert_eq!(apply(DOT_CONFIG, &BTreeMap::new()), DOT_CONFIG);
+    }
+
+    #[test]
+    fn translate_lv_conf_values() {
+        let symbols = Symbols::parse(LVGL_KCONFIG);
+        let translated = symbols.translate(&options(
+            r#"
+            LV_USE_DRAW_SW_ASM = 1
+            CONFIG_LV_COLOR_DEPTH = 32
+            LV_USE_LOG = 0
+            LV_USE_PERF_MONITOR = 1
+            LV_MEM_SIZE_KILOBYTES = 1
+            LV_OPTLEVEL = "-O3"
+            "#,
+        ));
+        assert_eq!(
+            translated,
+            options(
+                r#"
+                LV_COLOR_DEPTH_32 = true
+                LV_COLOR_DEPTH_24 = false
+                LV_COLOR_DEPTH_16 = false
+                LV_COLOR_DEPTH_8 = false
+                LV_DRAW_SW_ASM_NONE = false
+                LV_DRAW_SW_ASM_NEON = true
+                LV_DRAW_SW_ASM_HELIUM = false
+                LV_DRAW_SW_ASM_CUSTOM = false
+                LV_USE_LOG = false
+                LV_USE_PERF_MONITOR = true
+                LV_MEM_SIZE_KILOBYTES = 1
+                LV_OPTLEVEL = "-O3"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_choice_value_is_kept() {
+        let symbols = Symbols::parse(LVGL_KCONFIG);
+        let translated = symbols.translate(&options("LV_USE_DRAW_SW_ASM = 7"));
+        assert_eq!(translated, options("LV_USE_DRAW_SW_ASM = 7"));
+    }
+
+    #[test]
+    fn dropped_settings() {
+        let requested = options(
+            r#"
+            LV_COLOR_DEPTH_16 = true
+            LV_USE_LOG = false
+            LV_USE_DEMO_BENCHMARK = true
+            LV_MEM_SIZE_KILOBYTES = 64
+            LV_USE_PERF_MONITOR = false
+            LV_DRAW_SW_ASM_NEON = true
+            "#,
+        );
+        assert_eq!(
+            dropped(DOT_CONFIG, &requested),
+            vec![
+                ("CONFIG_LV_DRAW_SW_ASM_NEON=y".to_string(), None),
+                (
+                    "CONFIG_LV_USE_DEMO_BENCHMARK=y".to_string(),
+                    Some("# CONFIG_LV_USE_DEMO_BENCHMARK is not set".to_string())
+                ),
+                (
+                    "# CONFIG_LV_USE_LOG is not set".to_string(),
+                    Some("CONFIG_LV_USE_LOG=y".to_string())
+                ),
+            ]
+        );
+    }
+}
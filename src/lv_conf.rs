@@ -0,0 +1,240 @@
+//! `lv_conf.h` defaults files read by LVGL's `scripts/generate_lv_conf.py`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::config::KconfigValue;
+
+/// Set `overrides` in a defaults file of `NAME value` lines, replacing the existing entries
+/// in place and appending the options it doesn't mention yet.
+pub fn apply_defaults(defaults: &str, overrides: &BTreeMap<String, KconfigValue>) -> String {
+    let mut applied = BTreeSet::new();
+    let mut result = String::with_capacity(defaults.len());
+
+    for line in defaults.lines() {
+        let name = line
+            .split_whitespace()
+            .next()
+            .filter(|_| !line.trim_start().starts_with('#'));
+        match name.and_then(|name| overrides.get_key_value(name)) {
+            Some((name, value)) => {
+                result.push_str(&defaults_line(name, value));
+                applied.insert(name);
+            }
+            None => result.push_str(line),
+        }
+        result.push('\n');
+    }
+
+    for (name, value) in overrides {
+        if !applied.contains(name) {
+            result.push_str(&defaults_line(name, value));
+            result.push('\n');
+        }
+    }
+
+    result
+}
+
+fn defaults_line(name: &str, value: &KconfigValue) -> String {
+    match value {
+        KconfigValue::Bool(value) => format!("{name} {}", u8::from(*value)),
+        KconfigValue::Integer(value) => format!("{name} {value}"),
+        // Written as is, so strings can be C expressions like `LV_COLOR_FORMAT_RGB565`.
+        KconfigValue::String(value) => format!("{name} {value}"),
+    }
+}
+
+/// `NAME value` line of a defaults file.
+#[derive(Debug, PartialEq)]
+pub struct DefaultsEntry<'a> {
+    pub line: u32,
+    pub name: &'a str,
+    pub value: &'a str,
+}
+
+/// Entries of a defaults file, skipping blank lines and `#` comments.
+pub fn parse_defaults(defaults: &str) -> Vec<DefaultsEntry<'_>> {
+    defaults
+        .lines()
+        .zip(1..)
+        .filter_map(|(line, number)| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            Some(DefaultsEntry {
+                line: number,
+                name,
+                value: value.trim(),
+            })
+        })
+        .collect()
+}
+
+/// Object-like `#define`s of a header in order, with their value stripped of comments.
+pub fn parse_defines(header: &str) -> Vec<(&str, &str)> {
+    header
+        .lines()
+        .filter_map(|line| {
+            let define = line.trim_start().strip_prefix('#')?.trim_start();
+            let define = define.strip_prefix("define")?;
+            if !define.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let define = define.trim_start();
+            let (name, value) = define
+                .split_once(char::is_whitespace)
+                .unwrap_or((define, ""));
+            if name.contains('(') {
+                return None;
+            }
+            let value = value
+                .split("/*")
+                .next()
+                .and_then(|value| value.split("//").next())
+                .unwrap_or_default()
+                .trim();
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// What's wrong with a defaults file, by line.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub line: u32,
+    pub message: String,
+}
+
+/// Outcome of checking a generated `lv_conf.h` against its template and defaults.
+#[derive(Debug)]
+pub struct Validation {
+    /// Defaults that don't exist in the template or didn't make it into the generated file
+    pub problems: Vec<Problem>,
+
+    /// Every option whose value differs from the template's, as `-`/`+` lines
+    pub diff: String,
+}
+
+pub fn validate(template: &str, defaults: &str, generated: &str) -> Validation {
+    let template_defines = parse_defines(template);
+    let generated_defines = parse_defines(generated);
+    let template_value = |name: &str| {
+        template_defines
+            .iter()
+            .find(|(define, _)| *define == name)
+            .map(|(_, value)| *value)
+    };
+    let generated_value = |name: &str| {
+        generated_defines
+            .iter()
+            .find(|(define, _)| *define == name)
+            .map(|(_, value)| *value)
+    };
+
+    let mut problems = Vec::new();
+    for entry in parse_defaults(defaults) {
+        match (template_value(entry.name), generated_value(entry.name)) {
+            (None, _) => problems.push(Problem {
+                line: entry.line,
+                message: format!("{} isn't an option of lv_conf_template.h", entry.name),
+            }),
+            (Some(_), Some(value)) if value == entry.value => {}
+            (Some(_), value) => problems.push(Problem {
+                line: entry.line,
+                message: format!(
+                    "{} is {} in the generated lv_conf.h instead of {}",
+                    entry.name,
+                    value.unwrap_or("undefined"),
+                    entry.value
+                ),
+            }),
+        }
+    }
+
+    let mut diff = String::new();
+    let mut seen = BTreeSet::new();
+    for (name, value) in &generated_defines {
+        if !seen.insert(*name) {
+            continue;
+        }
+        match template_value(name) {
+            Some(template) if template == *value => {}
+            Some(template) => {
+                diff.push_str(&format!("-#define {name} {template}\n"));
+                diff.push_str(&format!("+#define {name} {value}\n"));
+            }
+            None => diff.push_str(&format!("+#define {name} {value}\n")),
+        }
+    }
+
+    Validation { problems, diff }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides() {
+        let defaults =
+            "# Display\nLV_COLOR_DEPTH 16\nLV_USE_DRAW_SW_ASM LV_DRAW_SW_ASM_NONE\nLV_USE_LOG 0\n";
+        let overrides = BTreeMap::from([
+            ("LV_USE_DRAW_SW_ASM".to_string(), KconfigValue::Integer(1)),
+            ("LV_USE_LOG".to_string(), KconfigValue::Bool(true)),
+            (
+                "LV_USE_DEMO_BENCHMARK".to_string(),
+                KconfigValue::Bool(true),
+            ),
+        ]);
+        assert_eq!(
+            apply_defaults(defaults, &overrides),
+            "# Display\nLV_COLOR_DEPTH 16\nLV_USE_DRAW_SW_ASM 1\nLV_USE_LOG 1\nLV_USE_DEMO_BENCHMARK 1\n"
+        );
+    }
+
+    const TEMPLATE: &str = r#"#if 0 /* Set this to "1" to enable content */
+#ifndef LV_CONF_H
+#define LV_CONF_H
+/** Color depth: 1 (I1), 8 (L8), 16 (RGB565), 24 (RGB888), 32 (XRGB8888) */
+#define LV_COLOR_DEPTH 16
+#define LV_USE_STDLIB_MALLOC    LV_STDLIB_BUILTIN
+#if LV_USE_STDLIB_MALLOC == LV_STDLIB_BUILTIN
+    #define LV_MEM_SIZE (64 * 1024U)          /**< [bytes] */
+#endif
+#define LV_USE_LOG 0
+#define LV_USE_DEMO_BENCHMARK 0
+#define LV_MAX(a, b) ((a) > (b) ? (a) : (b))
+#endif /*LV_CONF_H*/
+#endif /*End of "Content enable"*/
+"#;
+
+    #[test]
+    fn validates_defaults() {
+        let defaults = "# STM32U5G9J-DK2\n\nLV_COLOR_DEPTH 16\nLV_MEM_SIZE (256 * 1024U)\nLV_USE_DEMO_BENCHMRK 1\nLV_USE_LOG 1\n";
+        let generated = TEMPLATE
+            .replace("#if 0 ", "#if 1 ")
+            .replace("(64 * 1024U)", "(256 * 1024U)");
+
+        let validation = validate(TEMPLATE, defaults, &generated);
+        assert_eq!(
+            validation.problems,
+            [
+                Problem {
+                    line: 5,
+                    message: "LV_USE_DEMO_BENCHMRK isn't an option of lv_conf_template.h"
+                        .to_string()
+                },
+                Problem {
+                    line: 6,
+                    message: "LV_USE_LOG is 0 in the generated lv_conf.h instead of 1".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            validation.diff,
+            "-#define LV_MEM_SIZE (64 * 1024U)\n+#define LV_MEM_SIZE (256 * 1024U)\n"
+        );
+    }
+}
@@ -6,32 +6,63 @@ use std::{
     process::exit,
 };
 
+mod backtrace;
+mod benchmark;
+mod capture;
+mod clean;
 mod config;
+mod device_lock;
+mod diagnostics;
+mod discovery;
 mod error;
 mod esp32;
+mod esp_idf;
+mod esp_loader;
+mod firmware_size;
+mod flash;
+#[cfg(test)]
+mod golden;
+mod job;
+mod junit;
+mod kconfig;
+mod lv_conf;
+mod metrics;
 mod native;
+mod nuttx;
 mod prelude;
+mod reset;
 mod rzg3e;
+#[cfg(test)]
+mod scratch;
+#[cfg(test)]
+mod serial_sim;
+mod source_manifest;
 mod stm32;
 
-use config::EjLvBuilderConfig;
+use config::{EjLvBuilderConfig, ResultsLayout};
 use ej_builder_sdk::{Action, BuilderEvent, BuilderSdk};
 use tokio::process::Command;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
+    benchmark::SceneResult,
     esp32::{build_esp32s3, run_esp32s3},
+    job::Job,
     native::{build_cmake_native, run_native},
+    nuttx::{build_nuttx, run_nuttx},
     prelude::*,
     rzg3e::{build_rzg3e, kill_rzg3e, run_rzg3e},
     stm32::{build_stm32, run_stm32},
 };
 
-type BuildFn = fn(&BuilderSdk) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
-type RunFn = fn(&BuilderSdk) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
-type KillFn = fn(&BuilderSdk) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+type BuildFn = fn(&Job) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+type RunFn = fn(&Job) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+type KillFn = fn(&Job) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
 
 struct BoardConfig {
+    /// Results file name used before names were derived from the board and config,
+    /// kept for `results.layout = "legacy"`
+    legacy_results_name: &'static str,
     build_fn: BuildFn,
     run_fn: RunFn,
     kill_fn: KillFn,
@@ -47,6 +78,7 @@ fn get_board_configs() -> HashMap<&'static str, BoardConfig> {
     configs.insert(
         "SER8",
         BoardConfig {
+            legacy_results_name: "ser8",
             build_fn: |sdk| Box::pin(build_cmake_native(sdk)),
             run_fn: |sdk| Box::pin(run_native(sdk)),
             kill_fn: |_| Box::pin(no_kill()),
@@ -56,6 +88,7 @@ fn get_board_configs() -> HashMap<&'static str, BoardConfig> {
     configs.insert(
         "esp32s3",
         BoardConfig {
+            legacy_results_name: "esp32s3",
             build_fn: |sdk| Box::pin(build_esp32s3(sdk)),
             run_fn: |sdk| Box::pin(run_esp32s3(sdk)),
             kill_fn: |_| Box::pin(no_kill()),
@@ -65,6 +98,7 @@ fn get_board_configs() -> HashMap<&'static str, BoardConfig> {
     configs.insert(
         "Renesas RZ/G3E",
         BoardConfig {
+            legacy_results_name: "renesas-rzg3e",
             build_fn: |sdk| Box::pin(build_rzg3e(sdk)),
             run_fn: |sdk| Box::pin(run_rzg3e(sdk)),
             kill_fn: |sdk| Box::pin(kill_rzg3e(sdk)),
@@ -74,6 +108,7 @@ fn get_board_configs() -> HashMap<&'static str, BoardConfig> {
     configs.insert(
         "stm32u5g9",
         BoardConfig {
+            legacy_results_name: "stm32u5g9",
             build_fn: |sdk| Box::pin(build_stm32(sdk)),
             run_fn: |sdk| Box::pin(run_stm32(sdk)),
             kill_fn: |_| Box::pin(no_kill()),
@@ -105,17 +140,66 @@ pub fn board_folder(config_path: &Path, board_name: &str) -> PathBuf {
     workspace_folder(config_path).join(board_name)
 }
 
-fn results_path(config_path: &Path, config_name: &str) -> PathBuf {
-    workspace_folder(config_path).join(format!("results-{}", config_name))
+/// Lowercase `name` and collapse every run of non-alphanumeric characters into a single `-`,
+/// e.g. "Renesas RZ/G3E" becomes "renesas-rz-g3e".
+pub fn slug(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
 }
 
-struct BuildProcess {
+fn results_path(
+    config_path: &Path,
+    layout: ResultsLayout,
+    board_name: &str,
+    board_config_name: &str,
+    legacy_name: &str,
+) -> PathBuf {
+    let workspace = workspace_folder(config_path);
+    match layout {
+        ResultsLayout::Flat => workspace.join(format!(
+            "results-{}-{}",
+            slug(board_name),
+            slug(board_config_name)
+        )),
+        ResultsLayout::Directory => workspace
+            .join("results")
+            .join(slug(board_name))
+            .join(slug(board_config_name)),
+        ResultsLayout::Legacy => workspace.join(format!("results-{legacy_name}")),
+    }
+}
+
+/// Path of a result file stored next to the results, e.g. `results-esp32s3-eve.size.toml`.
+/// The legacy names are only kept for the results file itself, the other files are named like
+/// the flat layout so board configs of the same board don't overwrite each other's.
+fn results_artifact_path(
+    config_path: &Path,
+    layout: ResultsLayout,
+    board_name: &str,
+    board_config_name: &str,
+    extension: &str,
+) -> PathBuf {
+    let layout = match layout {
+        ResultsLayout::Legacy => ResultsLayout::Flat,
+        layout => layout,
+    };
+    let mut path =
+        results_path(config_path, layout, board_name, board_config_name, "").into_os_string();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+struct BuildProcess<'a> {
     config_path: PathBuf,
     /// Parsed workspace-level config controlling where build files come from.
-    ej_config: EjLvBuilderConfig,
+    ej_config: &'a EjLvBuilderConfig,
 }
 
-impl BuildProcess {
+impl BuildProcess<'_> {
     fn lvgl_repo_path(&self) -> PathBuf {
         workspace_folder(&self.config_path).join("lvgl-master")
     }
@@ -353,7 +437,7 @@ impl BuildProcess {
     }
 }
 
-impl Drop for BuildProcess {
+impl Drop for BuildProcess<'_> {
     fn drop(&mut self) {
         info!("Resetting git folder");
 
@@ -373,46 +457,88 @@ impl Drop for BuildProcess {
     }
 }
 
+/// Backend of boards that are only known from their `nuttx` settings.
+fn nuttx_board_config() -> BoardConfig {
+    BoardConfig {
+        legacy_results_name: "nuttx",
+        build_fn: |sdk| Box::pin(build_nuttx(sdk)),
+        run_fn: |sdk| Box::pin(run_nuttx(sdk)),
+        kill_fn: |_| Box::pin(no_kill()),
+    }
+}
+
+fn board_config(sdk: &BuilderSdk, config: &EjLvBuilderConfig) -> BoardConfig {
+    get_board_configs()
+        .remove(sdk.board_name())
+        .or_else(|| {
+            config
+                .board_config(sdk.board_name(), sdk.board_config_name())
+                .nuttx
+                .map(|_| nuttx_board_config())
+        })
+        .unwrap_or_else(|| panic!("Unsupported board: {}", sdk.board_name()))
+}
+
+/// Load the workspace config and pick the backend of the job's board.
+async fn load_job(sdk: BuilderSdk) -> Result<(Job, BoardConfig)> {
+    let config = EjLvBuilderConfig::load(&workspace_folder(&sdk.config_path())).await?;
+    let board_config = board_config(&sdk, &config);
+    let job = Job::load(sdk, config, board_config.legacy_results_name).await?;
+    Ok((job, board_config))
+}
+
 pub async fn build(sdk: BuilderSdk) -> Result<()> {
-    let workspace = workspace_folder(&sdk.config_path());
-    let ej_config = EjLvBuilderConfig::load(&workspace).await?;
+    let (job, board_config) = load_job(sdk).await?;
 
     info!(
         "Using build files from remote='{}' commit='{}'",
-        ej_config.build_files.remote, ej_config.build_files.commit
+        job.config.build_files.remote, job.config.build_files.commit
     );
 
     let build_process = BuildProcess {
-        config_path: sdk.config_path().clone(),
-        ej_config,
+        config_path: job.sdk.config_path(),
+        ej_config: &job.config,
     };
 
-    build_process.fetch_build_files().await?;
+    let result = match job
+        .step("fetch build files", build_process.fetch_build_files())
+        .await
+    {
+        Ok(()) => (board_config.build_fn)(&job).await,
+        Err(err) => Err(err),
+    };
 
-    let configs = get_board_configs();
-    let board_config = configs
-        .get(sdk.board_name())
-        .expect(&format!("Unsupported board: {}", sdk.board_name()));
+    if let Err(err) = diagnostics::write_summary(&job).await {
+        warn!("Failed to write the diagnostics summary: {err}");
+    }
+    write_reports(&job, &result, None).await;
+    result
+}
 
-    (board_config.build_fn)(&sdk).await
+/// Reports only describe the job, failing to write them must not change its result.
+async fn write_reports(job: &Job, result: &Result<()>, baseline: Option<&[SceneResult]>) {
+    if let Err(err) = junit::write_report(job, result, baseline).await {
+        warn!("Failed to write the JUnit report: {err}");
+    }
+    if let Err(err) = metrics::export(job, result).await {
+        warn!("Failed to export metrics: {err}");
+    }
 }
 
 pub async fn run(sdk: BuilderSdk) -> Result<()> {
-    let configs = get_board_configs();
-    let board_config = configs
-        .get(sdk.board_name())
-        .expect(&format!("Unsupported board: {}", sdk.board_name()));
+    let (job, board_config) = load_job(sdk).await?;
+
+    let baseline = junit::read_baseline(&job).await;
+    let result = (board_config.run_fn)(&job).await;
 
-    (board_config.run_fn)(&sdk).await
+    write_reports(&job, &result, baseline.as_deref()).await;
+    result
 }
 
 pub async fn kill(sdk: BuilderSdk) -> Result<()> {
-    let configs = get_board_configs();
-    let board_config = configs
-        .get(sdk.board_name())
-        .expect(&format!("Unsupported board: {}", sdk.board_name()));
+    let (job, board_config) = load_job(sdk).await?;
 
-    (board_config.kill_fn)(&sdk).await
+    (board_config.kill_fn)(&job).await
 }
 
 #[tokio::main]
@@ -437,3 +563,65 @@ async fn main() -> Result<()> {
         Action::Run => run(sdk).await,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slug_normalizes_names() {
+        assert_eq!(slug("esp32s3"), "esp32s3");
+        assert_eq!(slug("Renesas RZ/G3E"), "renesas-rz-g3e");
+        assert_eq!(slug("STM32U5G9J-DK2"), "stm32u5g9j-dk2");
+        assert_eq!(slug("  nuttx__(SMP) v2.0!  "), "nuttx-smp-v2-0");
+        assert_eq!(slug("Écran tactile"), "cran-tactile");
+        assert_eq!(slug("///"), "");
+    }
+
+    #[test]
+    fn results_paths() {
+        let config_path = Path::new("/work/ejlv/config.toml");
+        let workspace = workspace_folder(config_path);
+        let path = |layout| results_path(config_path, layout, "Renesas RZ/G3E", "Wayland", "rzg3e");
+
+        assert_eq!(
+            path(ResultsLayout::default()),
+            workspace.join("results-rzg3e")
+        );
+        assert_eq!(
+            path(ResultsLayout::Flat),
+            workspace.join("results-renesas-rz-g3e-wayland")
+        );
+        assert_eq!(
+            path(ResultsLayout::Directory),
+            workspace.join("results/renesas-rz-g3e/wayland")
+        );
+    }
+
+    #[test]
+    fn results_artifact_paths() {
+        let config_path = Path::new("/work/ejlv/config.toml");
+        let workspace = workspace_folder(config_path);
+        let path = |layout, config| {
+            results_artifact_path(config_path, layout, "esp32s3", config, "size.toml")
+        };
+
+        // Board configs sharing a legacy results name still get their own files.
+        assert_eq!(
+            path(ResultsLayout::Legacy, "eve"),
+            workspace.join("results-esp32s3-eve.size.toml")
+        );
+        assert_eq!(
+            path(ResultsLayout::Legacy, "nuttx"),
+            workspace.join("results-esp32s3-nuttx.size.toml")
+        );
+        assert_eq!(
+            path(ResultsLayout::Flat, "eve"),
+            workspace.join("results-esp32s3-eve.size.toml")
+        );
+        assert_eq!(
+            path(ResultsLayout::Directory, "eve"),
+            workspace.join("results/esp32s3/eve.size.toml")
+        );
+    }
+}
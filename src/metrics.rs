@@ -0,0 +1,312 @@
+//! OpenMetrics text export of the job metrics for the node-exporter textfile collector.
+
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ej_builder_sdk::Action;
+use tokio::process::Command;
+use tracing::info;
+
+use crate::benchmark::parse_scenes;
+use crate::firmware_size::{self, REPORT_EXTENSION, SizeReport};
+use crate::job::{Job, Step};
+use crate::prelude::*;
+use crate::{lvgl_folder, workspace_folder};
+
+/// A gauge metric family and its samples.
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(Vec<(&'static str, String)>, f64)>,
+}
+
+impl Family {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn sample(&mut self, labels: Vec<(&'static str, String)>, value: f64) {
+        self.samples.push((labels, value));
+    }
+}
+
+/// What a finished job exports, gathered from the job so the families can be built without it.
+struct JobMetrics {
+    action: &'static str,
+    /// Labels shared by every sample: board, config and LVGL commit
+    labels: Vec<(&'static str, String)>,
+    success: bool,
+    /// Unix time at which the job finished
+    finished_at: f64,
+    steps: Vec<Step>,
+    output: Option<String>,
+    size_report: Option<SizeReport>,
+}
+
+/// Write the metrics of `job` into the configured folder, if any.
+pub async fn export(job: &Job, result: &Result<()>) -> Result<()> {
+    let Some(dir) = &job.config.metrics.dir else {
+        return Ok(());
+    };
+
+    let action: &'static str = job.sdk.action().into();
+    // Only the build exports sizes, the collector rejects series repeated across files.
+    let size_report = match job.sdk.action() {
+        Action::Build => {
+            firmware_size::read_report(&job.results_artifact_path(REPORT_EXTENSION)).await
+        }
+        Action::Run => None,
+    };
+    let metrics = JobMetrics {
+        action,
+        labels: vec![
+            ("board", job.sdk.board_name().to_string()),
+            ("config", job.sdk.board_config_name().to_string()),
+            ("lvgl_sha", lvgl_sha(job).await),
+        ],
+        success: result.is_ok(),
+        finished_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        steps: job.steps(),
+        output: job.output(),
+        size_report,
+    };
+
+    let dir = workspace_folder(&job.sdk.config_path()).join(dir);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    // The textfile collector may read at any time, so write to a temporary file and rename it.
+    let path = dir.join(format!("ejlv_{}_{}.prom", job.slug(), action));
+    let tmp_path = dir.join(format!(".ejlv_{}_{}.prom.tmp", job.slug(), action));
+    tokio::fs::write(&tmp_path, render(&families(&metrics))).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    info!("Metrics written to {}", path.display());
+
+    Ok(())
+}
+
+fn families(metrics: &JobMetrics) -> Vec<Family> {
+    let action = metrics.action;
+    let labels = |extra: &[(&'static str, String)]| {
+        let mut labels = metrics.labels.clone();
+        labels.extend_from_slice(extra);
+        labels
+    };
+
+    let mut families = Vec::new();
+
+    let mut success = Family::new("ejlv_job_success", "Whether the last job succeeded");
+    success.sample(
+        labels(&[("action", action.to_string())]),
+        if metrics.success { 1.0 } else { 0.0 },
+    );
+    families.push(success);
+
+    let mut timestamp = Family::new(
+        "ejlv_job_timestamp_seconds",
+        "Unix time at which the last job finished",
+    );
+    timestamp.sample(
+        labels(&[("action", action.to_string())]),
+        metrics.finished_at,
+    );
+    families.push(timestamp);
+
+    let mut durations = Family::new(
+        "ejlv_step_duration_seconds",
+        "Time taken by each build or run step",
+    );
+    for step in &metrics.steps {
+        durations.sample(
+            labels(&[("action", action.to_string()), ("step", step.name.clone())]),
+            step.duration.as_secs_f64(),
+        );
+    }
+    families.push(durations);
+
+    if let Some(output) = &metrics.output {
+        let mut fps = Family::new("ejlv_scene_fps", "Average frames per second of a scene");
+        let mut cpu = Family::new("ejlv_scene_cpu_percent", "Average CPU usage of a scene");
+        let mut frame_time = Family::new(
+            "ejlv_scene_frame_time_seconds",
+            "Average frame time of a scene",
+        );
+        let mut render_time = Family::new(
+            "ejlv_scene_render_time_seconds",
+            "Average render time of a scene",
+        );
+        let mut flush_time = Family::new(
+            "ejlv_scene_flush_time_seconds",
+            "Average flush time of a scene",
+        );
+
+        for scene in parse_scenes(output) {
+            let scene_labels = labels(&[("scene", scene.name)]);
+            fps.sample(scene_labels.clone(), scene.fps as f64);
+            cpu.sample(scene_labels.clone(), scene.cpu as f64);
+            frame_time.sample(scene_labels.clone(), scene.avg_time as f64 / 1000.0);
+            render_time.sample(scene_labels.clone(), scene.render_time as f64 / 1000.0);
+            flush_time.sample(scene_labels, scene.flush_time as f64 / 1000.0);
+        }
+
+        families.extend([fps, cpu, frame_time, render_time, flush_time]);
+    }
+
+    if let Some(report) = &metrics.size_report {
+        let mut size = Family::new("ejlv_firmware_size_bytes", "Firmware flash and RAM usage");
+        size.sample(
+            labels(&[("region", "flash".to_string())]),
+            report.flash as f64,
+        );
+        size.sample(labels(&[("region", "ram".to_string())]), report.ram as f64);
+        families.push(size);
+
+        if let Some(lvgl) = report.lvgl {
+            let mut size = Family::new("ejlv_lvgl_size_bytes", "LVGL flash and RAM usage");
+            size.sample(
+                labels(&[("region", "flash".to_string())]),
+                lvgl.flash() as f64,
+            );
+            size.sample(labels(&[("region", "ram".to_string())]), lvgl.ram() as f64);
+            families.push(size);
+        }
+    }
+
+    families
+}
+
+async fn lvgl_sha(job: &Job) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(lvgl_folder(&job.sdk.config_path()))
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .await;
+
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+fn render(families: &[Family]) -> String {
+    let mut text = String::new();
+
+    for family in families.iter().filter(|family| !family.samples.is_empty()) {
+        let _ = writeln!(text, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(text, "# TYPE {} gauge", family.name);
+
+        for (labels, value) in &family.samples {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+                .collect();
+            let _ = writeln!(text, "{}{{{}}} {}", family.name, labels.join(","), value);
+        }
+    }
+
+    text.push_str("# EOF\n");
+    text
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::firmware_size::Totals;
+    use crate::golden::assert_golden;
+
+    const ESP32S3_OUTPUT: &str = "\
+Benchmark Start
+Name,Avg. CPU,Avg. FPS,Avg. time,render time,flush time
+Empty screen,11%,29,1,1,0
+Multiple \"labels\",60%,26,20,17,3
+Benchmark Over
+";
+
+    #[test]
+    fn rendered_build() {
+        let totals = Totals {
+            text: 300_000,
+            rodata: 100_000,
+            data: 20_000,
+            bss: 80_000,
+        };
+        let lvgl = Totals {
+            text: 200_000,
+            rodata: 60_000,
+            data: 1_000,
+            bss: 40_000,
+        };
+        let metrics = JobMetrics {
+            action: "build",
+            labels: vec![
+                ("board", "esp32s3".to_string()),
+                ("config", r"eve\nuttx".to_string()),
+                ("lvgl_sha", "unknown".to_string()),
+            ],
+            success: false,
+            finished_at: 1_760_000_000.5,
+            steps: vec![
+                Step {
+                    name: "fetch build files".to_string(),
+                    duration: Duration::from_millis(1_250),
+                    failure: None,
+                },
+                Step {
+                    name: "idf.py build\n(attempt 2)".to_string(),
+                    duration: Duration::from_secs(95),
+                    failure: Some("idf.py build exited with 1".to_string()),
+                },
+            ],
+            output: None,
+            size_report: Some(SizeReport {
+                elf: PathBuf::from("build/lv_esp32s3.elf"),
+                flash: totals.flash(),
+                ram: totals.ram(),
+                totals,
+                lvgl: Some(lvgl),
+                baseline_delta: None,
+                sections: Vec::new(),
+                archives: Vec::new(),
+                lvgl_objects: Vec::new(),
+            }),
+        };
+        assert_golden("metrics/build.prom", &render(&families(&metrics)));
+    }
+
+    #[test]
+    fn rendered_run() {
+        let metrics = JobMetrics {
+            action: "run",
+            labels: vec![
+                ("board", "esp32s3".to_string()),
+                ("config", "eve".to_string()),
+                ("lvgl_sha", "0123456789abcdef".to_string()),
+            ],
+            success: true,
+            finished_at: 1_760_000_100.0,
+            steps: Vec::new(),
+            output: Some(ESP32S3_OUTPUT.to_string()),
+            size_report: None,
+        };
+        assert_golden("metrics/run.prom", &render(&families(&metrics)));
+    }
+}
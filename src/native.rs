@@ -1,10 +1,9 @@
 use std::path::{Path, PathBuf};
 
-use ej_builder_sdk::BuilderSdk;
 use tokio::process::Command;
 use tracing::info;
 
-use crate::{board_folder, prelude::*, results_path};
+use crate::{board_folder, job::Job, prelude::*};
 
 fn build_folder(config_path: &Path, config_name: &str) -> PathBuf {
     board_folder(config_path, "lv_port_linux").join(format!("build-native-{config_name}"))
@@ -16,40 +15,41 @@ fn target_path(config_path: &Path, config_name: &str) -> PathBuf {
         .join("lvglsim")
 }
 
-pub async fn build_cmake_native(sdk: &BuilderSdk) -> Result<()> {
+pub async fn build_cmake_native(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
     let nprocs = num_cpus::get();
 
     let project_path = board_folder(&sdk.config_path(), "lv_port_linux");
 
     let build_path = build_folder(&sdk.config_path(), sdk.board_config_name());
 
-    let result = Command::new("cmake")
-        .arg("-B")
-        .arg(&build_path)
-        .arg("-S")
-        .arg(project_path)
-        .arg(format!("-DCONFIG={}", sdk.board_config_name()))
-        .spawn()?
-        .wait()
-        .await?;
-
-    assert!(result.success());
-
-    let result = Command::new("cmake")
-        .arg("--build")
-        .arg(&build_path)
-        .arg("-j")
-        .arg(nprocs.to_string())
-        .spawn()?
-        .wait()
-        .await?;
-
-    assert!(result.success());
+    job.run_step(
+        "cmake configure",
+        Command::new("cmake")
+            .arg("-B")
+            .arg(&build_path)
+            .arg("-S")
+            .arg(project_path)
+            .arg(format!("-DCONFIG={}", sdk.board_config_name())),
+    )
+    .await?;
+
+    job.run_step(
+        "cmake build",
+        Command::new("cmake")
+            .arg("--build")
+            .arg(&build_path)
+            .arg("-j")
+            .arg(nprocs.to_string()),
+    )
+    .await?;
+
     Ok(())
 }
 
-pub async fn run_native(sdk: &BuilderSdk) -> Result<()> {
-    let results_p = results_path(&sdk.config_path(), "ser8");
+pub async fn run_native(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
+    let results_p = job.results_path();
     if sdk.board_config_name().starts_with("glfw") {
         std::fs::write(&results_p, "Skip")?;
         return Ok(());
@@ -66,8 +66,14 @@ pub async fn run_native(sdk: &BuilderSdk) -> Result<()> {
 
     // Dump output first so that we have them in the logs before checking if it failed
     info!("{}\n{}", stdout, stderr);
+    job.record_output(&format!("{}\n{}", stdout, stderr));
 
-    assert!(result.status.success(), "Native run failed");
+    if !result.status.success() {
+        return Err(Error::CommandFailed(format!(
+            "Native run exited with {}",
+            result.status
+        )));
+    }
 
     std::fs::write(&results_p, format!("{}\n{}", stdout, stderr))?;
 
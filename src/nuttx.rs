@@ -0,0 +1,294 @@
+//! NuttX builds of LVGL for any board NuttX supports, configured by `[boards.<board>.<config>.nuttx]`.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::process::Command;
+use tokio_serial::SerialPortBuilderExt;
+use tracing::info;
+
+use crate::capture::session;
+use crate::clean;
+use crate::config::{
+    BoardConfigSettings, ConsoleStep, NuttxConfig, NuttxKconfig, PromptConfig, SerialPortConfig,
+};
+use crate::device_lock::DeviceLock;
+use crate::discovery;
+use crate::firmware_size;
+use crate::job::Job;
+use crate::kconfig;
+use crate::prelude::*;
+use crate::workspace_folder;
+
+/// Folder holding the `nuttx` and `apps` checkouts.
+pub fn project_path(job: &Job, config: &NuttxConfig) -> PathBuf {
+    workspace_folder(&job.sdk.config_path()).join(&config.project)
+}
+
+/// Firmware image saved next to the checkouts after a build.
+pub fn firmware_bin(job: &Job, config: &NuttxConfig) -> PathBuf {
+    project_path(job, config).join("nuttx.bin")
+}
+
+/// ELF saved next to the checkouts after a build, to decode crash backtraces.
+pub fn firmware_elf(job: &Job, config: &NuttxConfig) -> PathBuf {
+    project_path(job, config).join("nuttx.elf")
+}
+
+/// Wait for the NuttShell prompt and start `app` unless configured otherwise.
+pub fn console_defaults(settings: &mut BoardConfigSettings, app: &str) {
+    settings.prompt.get_or_insert_with(|| PromptConfig {
+        text: "nsh> ".to_string(),
+        timeout_secs: 30,
+    });
+    settings.console.before.get_or_insert_with(|| {
+        vec![ConsoleStep {
+            send: Some(format!("{app}\n")),
+            expect: None,
+            timeout_secs: 0,
+        }]
+    });
+}
+
+/// Kconfig hooking LVGL's own Kconfig into the NuttX apps menu, under `kconfig.symbol`.
+pub fn kconfig_wrapper(kconfig: &NuttxKconfig, lvgl_kconfig: &str) -> String {
+    format!(
+        r#"#
+# For a description of the syntax of this configuration file,
+# see the file kconfig-language.txt in the NuttX tools repository.
+#
+
+menuconfig {symbol}
+	bool "{prompt}"
+	default n
+	---help---
+		Enable support for the LVGL GUI library.
+
+if {symbol}
+
+{lvgl_kconfig}
+{extra}
+endif # {symbol}
+"#,
+        symbol = kconfig.symbol,
+        prompt = kconfig.prompt,
+        extra = kconfig.extra,
+    )
+}
+
+async fn nuttx_clean(job: &Job, config: &NuttxConfig) -> Result<()> {
+    let project_path = project_path(job, config);
+    let nuttx_path = project_path.join("nuttx");
+    let lvgl_app_path = project_path.join("apps").join(&config.lvgl_app_dir);
+    let lvgl_path = lvgl_app_path.join("lvgl");
+
+    let _ = Command::new("make")
+        .arg("-C")
+        .arg(&nuttx_path)
+        .arg("distclean")
+        .spawn()?
+        .wait()
+        .await?;
+
+    // Done defensively in case distclean's rules weren't generated properly. The LVGL
+    // checkout is usually linked into the app folder, so it's cleaned on its own.
+    clean::remove_build_products(&lvgl_app_path).await?;
+    clean::remove_build_products(&lvgl_path).await?;
+    clean::verify_clean(&lvgl_path).await?;
+
+    Ok(())
+}
+
+/// Configure and build NuttX with LVGL, saving the firmware next to the checkouts.
+pub async fn build(job: &Job, config: &NuttxConfig) -> Result<()> {
+    let project_path = std::path::absolute(project_path(job, config))?;
+    let nuttx_path = project_path.join("nuttx");
+    let lvgl_app_path = project_path.join("apps").join(&config.lvgl_app_dir);
+
+    info!("Cleaning nuttx build files");
+    nuttx_clean(job, config).await?;
+
+    info!("Setting up nuttx config");
+    let lvgl_kconfig =
+        tokio::fs::read_to_string(lvgl_app_path.join("lvgl").join("Kconfig")).await?;
+    tokio::fs::write(
+        lvgl_app_path.join("Kconfig"),
+        kconfig_wrapper(&config.kconfig, &lvgl_kconfig),
+    )
+    .await?;
+
+    job.run_step(
+        "nuttx configure",
+        Command::new(nuttx_path.join("tools").join("configure.sh"))
+            .current_dir(&nuttx_path)
+            .arg("-l")
+            .arg(&config.target),
+    )
+    .await?;
+
+    // NuttX's LVGL options are named after lv_conf.h, the board's own options win.
+    let mut overrides = kconfig::Symbols::parse(&lvgl_kconfig).translate(&job.settings.lv_conf);
+    overrides.extend(kconfig::unprefixed(&config.defconfig));
+
+    if !overrides.is_empty() {
+        let config_path = nuttx_path.join(".config");
+        let configured = tokio::fs::read_to_string(&config_path).await?;
+        tokio::fs::write(&config_path, kconfig::apply(&configured, &overrides)).await?;
+
+        // Let Kconfig resolve the dependencies of the options that were set.
+        job.run_step(
+            "nuttx olddefconfig",
+            Command::new("make")
+                .current_dir(&nuttx_path)
+                .arg("olddefconfig"),
+        )
+        .await?;
+
+        let resolved = tokio::fs::read_to_string(&config_path).await?;
+        kconfig::warn_dropped(&resolved, &overrides);
+    }
+
+    info!("Building nuttx");
+    let project = project_path.to_string_lossy();
+    job.run_step(
+        "nuttx build",
+        Command::new("make")
+            .current_dir(&nuttx_path)
+            .envs(
+                config
+                    .env
+                    .iter()
+                    .map(|(name, value)| (name, value.replace("{project}", &project))),
+            )
+            .arg(format!("-j{}", num_cpus::get()))
+            .arg("nuttx"),
+    )
+    .await?;
+
+    firmware_size::report(
+        job,
+        &nuttx_path.join("nuttx"),
+        Some(&nuttx_path.join("nuttx.map")),
+    )
+    .await?;
+
+    info!("Saving binary file");
+    tokio::fs::copy(nuttx_path.join("nuttx.bin"), firmware_bin(job, config)).await?;
+    // Kept for decoding crash backtraces, the clean below removes the build outputs.
+    tokio::fs::copy(nuttx_path.join("nuttx"), firmware_elf(job, config)).await?;
+
+    /* we need to clean this build so the lvgl dir isn't polluted with object files*/
+    info!("Cleaning nuttx build files");
+    nuttx_clean(job, config).await?;
+
+    Ok(())
+}
+
+fn config(job: &Job) -> Result<&NuttxConfig> {
+    job.settings.nuttx.as_ref().ok_or_else(|| {
+        Error::ConfigError(format!(
+            "No [boards.\"{}\".\"{}\".nuttx] section",
+            job.sdk.board_name(),
+            job.sdk.board_config_name()
+        ))
+    })
+}
+
+pub async fn build_nuttx(job: &Job) -> Result<()> {
+    build(job, config(job)?).await
+}
+
+/// Flash with the configured `flash` command, then run the benchmark from NSH on `app_port`.
+pub async fn run_nuttx(job: &Job) -> Result<()> {
+    let config = config(job)?;
+    let results_p = job.results_path();
+    let _ = std::fs::remove_file(&results_p);
+
+    let Some(app_port) = &job.settings.app_port else {
+        return Err(Error::ConfigError(
+            "NuttX boards need an app_port to run".to_string(),
+        ));
+    };
+    let flash_port = job.settings.flash_port.as_ref().unwrap_or(app_port);
+
+    let _lock = DeviceLock::acquire(job, &discovery::device_id(flash_port)?).await?;
+
+    let Some((program, args)) = config.flash.split_first() else {
+        return Err(Error::ConfigError(
+            "NuttX boards need a flash command to run".to_string(),
+        ));
+    };
+    let flash_port_path = find_port(flash_port)?;
+    let app_port_path = find_port(app_port)?;
+    let _flash_port_lock = DeviceLock::acquire_port(job, &flash_port_path).await?;
+    let _app_port_lock = if app_port_path == flash_port_path {
+        None
+    } else {
+        Some(DeviceLock::acquire_port(job, &app_port_path).await?)
+    };
+    let bin = firmware_bin(job, config);
+    let elf = firmware_elf(job, config);
+    let placeholder = |arg: &String| {
+        arg.replace("{bin}", &bin.to_string_lossy())
+            .replace("{elf}", &elf.to_string_lossy())
+            .replace("{port}", &flash_port_path)
+    };
+    job.run_step(
+        "nuttx flash",
+        Command::new(placeholder(program)).args(args.iter().map(placeholder)),
+    )
+    .await?;
+
+    let mut port = tokio_serial::new(&app_port_path, 115_200)
+        .timeout(Duration::from_secs(job.settings.capture.idle_timeout_secs))
+        .open_native_async()?;
+
+    let mut settings = job.settings.clone();
+    console_defaults(&mut settings, &config.app);
+
+    let mut output = String::new();
+    let result = session(
+        &mut port,
+        &settings,
+        &results_p,
+        &job.results_artifact_path("transcript.log"),
+        &mut output,
+    )
+    .await;
+    job.record_output(&output);
+    result
+}
+
+fn find_port(config: &SerialPortConfig) -> Result<String> {
+    discovery::find_port(config)?
+        .ok_or_else(|| Error::DeviceNotFound(format!("NuttX {}", discovery::describe(config))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::assert_golden;
+
+    const LVGL_KCONFIG: &str = include_str!("../testdata/nuttx/lvgl.Kconfig");
+
+    #[test]
+    fn default_kconfig_wrapper() {
+        assert_golden(
+            "nuttx/Kconfig.default.golden",
+            &kconfig_wrapper(&NuttxKconfig::default(), LVGL_KCONFIG),
+        );
+    }
+
+    #[test]
+    fn custom_kconfig_wrapper() {
+        let kconfig = NuttxKconfig {
+            symbol: "GRAPHICS_LVGL_BENCH".to_string(),
+            prompt: "LVGL for benchmarks".to_string(),
+            extra: String::new(),
+        };
+        assert_golden(
+            "nuttx/Kconfig.custom.golden",
+            &kconfig_wrapper(&kconfig, LVGL_KCONFIG),
+        );
+    }
+}
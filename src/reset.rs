@@ -0,0 +1,31 @@
+//! Board reset through the DTR/RTS lines of its serial port.
+
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tokio_serial::SerialPort;
+use tracing::info;
+
+use crate::config::ResetStep;
+use crate::prelude::*;
+
+/// Drive DTR and RTS through `sequence`, e.g. pulse RTS (wired to EN on most ESP32 boards)
+/// to reboot into the application right after the port was opened.
+pub async fn reset(port: &mut impl SerialPort, sequence: &[ResetStep]) -> Result<()> {
+    if sequence.is_empty() {
+        return Ok(());
+    }
+
+    info!("Resetting board through DTR/RTS");
+    for step in sequence {
+        if let Some(dtr) = step.dtr {
+            port.write_data_terminal_ready(dtr)?;
+        }
+        if let Some(rts) = step.rts {
+            port.write_request_to_send(rts)?;
+        }
+        sleep(Duration::from_millis(step.delay_ms)).await;
+    }
+
+    Ok(())
+}
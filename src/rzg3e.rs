@@ -1,12 +1,15 @@
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
 
-use ej_builder_sdk::BuilderSdk;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::Command;
-use tracing::info;
+use tokio::time::timeout;
+use tracing::{info, warn};
 
-use crate::{board_folder, prelude::*, results_path};
-
-const RZG3E_ADDRESS: &str = "192.168.1.172";
+use crate::capture::{Transcript, capture};
+use crate::config::{SshConfig, UnreachableAction};
+use crate::{board_folder, job::Job, prelude::*};
 
 fn build_folder(config_path: &Path, config_name: &str) -> PathBuf {
     board_folder(config_path, "lv_port_linux").join(format!("build-{config_name}"))
@@ -18,103 +21,287 @@ fn target_path(config_path: &Path, config_name: &str) -> PathBuf {
         .join("lvglsim")
 }
 
-pub async fn build_rzg3e(sdk: &BuilderSdk) -> Result<()> {
+pub async fn build_rzg3e(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
     let nprocs = num_cpus::get();
 
     let project_path = board_folder(&sdk.config_path(), "lv_port_linux");
 
     let build_path = build_folder(&sdk.config_path(), sdk.board_config_name());
 
-    let result = Command::new("bash")
-        .arg("-c")
-        .arg(&format!(
+    job.run_step(
+        "cmake configure",
+        Command::new("bash").arg("-c").arg(format!(
             ". /opt/rz-vlp/5.0.8/environment-setup-cortexa55-poky-linux && cmake -DCONFIG={} -B {} -S {}",
             sdk.board_config_name(),
             build_path.display(),
             project_path.display(),
-        ))
-        .spawn()?
-        .wait()
-        .await?;
+        )),
+    )
+    .await?;
 
-    assert!(result.success());
-
-    let result = Command::new("cmake")
-        .arg("--build")
-        .arg(&build_path)
-        .arg("-j")
-        .arg(nprocs.to_string())
-        .spawn()?
-        .wait()
-        .await?;
+    job.run_step(
+        "cmake build",
+        Command::new("cmake")
+            .arg("--build")
+            .arg(&build_path)
+            .arg("-j")
+            .arg(nprocs.to_string()),
+    )
+    .await?;
 
-    assert!(result.success());
     Ok(())
 }
 
-pub async fn run_rzg3e(sdk: &BuilderSdk) -> Result<()> {
-    info!("Benchmark runs on the rzg3e are disabled for now.");
-    let results_p = results_path(&sdk.config_path(), "renesas-rzg3e");
-    std::fs::write(&results_p, "Skip")?;
+fn ssh_target(job: &Job) -> SshConfig {
+    job.settings.ssh.clone().unwrap_or_default()
+}
 
-    return Ok(());
+/// Options shared by `ssh` and `scp`. Never prompt, a job has nobody to answer.
+fn ssh_options(command: &mut Command, target: &SshConfig) {
+    command
+        .arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg(format!("ConnectTimeout={}", target.connect_timeout_secs));
+    if let Some(identity_file) = &target.identity_file {
+        command.arg("-i").arg(identity_file);
+    }
+}
 
-    let _ = std::fs::remove_file(&results_p);
+/// `value` as a single word for the remote shell.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// `ssh` running `remote_command` on the target.
+fn ssh(target: &SshConfig, remote_command: &str) -> Command {
+    let mut command = Command::new("ssh");
+    ssh_options(&mut command, target);
+    command
+        .arg("-p")
+        .arg(target.port.to_string())
+        .arg(format!("{}@{}", target.user, target.host))
+        .arg(remote_command);
+    command
+}
 
-    let path = target_path(&sdk.config_path(), sdk.board_config_name());
+/// Copy `path` to the target's remote folder. scp uses SFTP since OpenSSH 9.0, which takes the
+/// remote path as is, so `remote_dir` isn't quoted here.
+fn scp(target: &SshConfig, path: &Path) -> Command {
+    let mut command = Command::new("scp");
+    ssh_options(&mut command, target);
+    command
+        .arg("-P")
+        .arg(target.port.to_string())
+        .arg(path)
+        .arg(format!(
+            "{}@{}:{}/",
+            target.user, target.host, target.remote_dir
+        ));
+    command
+}
 
-    let result = Command::new("scp")
-        .arg(&path)
-        .arg(&format!("root@{}:~", RZG3E_ADDRESS))
-        .spawn()?
-        .wait()
+/// Whether the target accepts an SSH login within its connect timeout. Only failing to connect
+/// means unreachable, a rejected login or a bad option is an error of its own.
+async fn reachable(target: &SshConfig) -> Result<bool> {
+    let output = ssh(target, "true")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
         .await?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(255) if is_connection_error(&stderr) => {
+            info!("ssh: {}", stderr.trim());
+            Ok(false)
+        }
+        _ => Err(Error::CommandFailed(format!(
+            "ssh to {}@{} exited with {}: {}",
+            target.user,
+            target.host,
+            output.status,
+            stderr.trim()
+        ))),
+    }
+}
+
+/// Whether ssh's `stderr` says it couldn't reach the host, as opposed to e.g. a rejected key.
+fn is_connection_error(stderr: &str) -> bool {
+    const CONNECTION_ERRORS: &[&str] = &[
+        "Connection refused",
+        "Connection timed out",
+        "Operation timed out",
+        "No route to host",
+        "Network is unreachable",
+        "Host is down",
+        "Could not resolve hostname",
+        "Connection reset by peer",
+        "Connection closed by",
+    ];
+    CONNECTION_ERRORS.iter().any(|error| stderr.contains(error))
+}
 
-    assert!(result.success(), "SCP execution failed");
+/// Copy the build to the board, then run it over SSH and capture its output like a serial console.
+pub async fn run_rzg3e(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
+    let target = ssh_target(job);
+    let results_p = job.results_path();
+    let _ = std::fs::remove_file(&results_p);
+
+    let destination = format!("{}@{}:{}", target.user, target.host, target.port);
+    if !job.step("ssh reachability", reachable(&target)).await? {
+        match target.when_unreachable {
+            UnreachableAction::Skip => {
+                warn!("{destination} is unreachable, skipping the benchmark run");
+                std::fs::write(&results_p, "Skip")?;
+                return Ok(());
+            }
+            UnreachableAction::Fail => {
+                return Err(Error::DeviceNotFound(format!(
+                    "RZ/G3E at {destination} is unreachable over SSH"
+                )));
+            }
+        }
+    }
+
+    let binary = target_path(&sdk.config_path(), sdk.board_config_name());
+    job.run_step("scp lvglsim", &mut scp(&target, &binary))
+        .await?;
 
-    if sdk.board_config_name() == "wayland" {
-        Command::new("ssh")
-            .arg(&format!("root@{}", RZG3E_ADDRESS))
-            .arg(&format!("systemctl start weston"))
-            .spawn()?
-            .wait_with_output()
-            .await?;
+    let weston = if sdk.board_config_name() == "wayland" {
+        "systemctl start weston"
     } else {
-        Command::new("ssh")
-            .arg(&format!("root@{}", RZG3E_ADDRESS))
-            .arg(&format!("systemctl stop weston.socket"))
-            .spawn()?
-            .wait_with_output()
-            .await?;
+        "systemctl stop weston.socket"
     };
+    // Not fatal, the image may not ship weston at all.
+    if !job.run_command(&mut ssh(&target, weston)).await?.success() {
+        warn!("\"{weston}\" failed on {destination}");
+    }
 
-    let result = Command::new("ssh")
-        .arg(&format!("root@{}", RZG3E_ADDRESS))
-        .arg(&format!("./lvglsim"))
-        .spawn()?
-        .wait_with_output()
-        .await?;
+    let transcript_path = job.results_artifact_path("transcript.log");
+    let mut transcript =
+        Transcript::start(tokio::fs::File::create(&transcript_path).await?).await?;
+    info!(
+        "Recording the remote output to {}",
+        transcript_path.display()
+    );
 
-    let stdout = String::from_utf8_lossy(&result.stdout);
-    let stderr = String::from_utf8_lossy(&result.stderr);
+    let mut child = ssh(
+        &target,
+        &format!("cd {} && ./lvglsim 2>&1", shell_quote(&target.remote_dir)),
+    )
+    .stdout(Stdio::piped())
+    .kill_on_drop(true)
+    .spawn()?;
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
 
-    // Dump output first so that we have them in the logs before checking if it failed
-    info!("{}\n{}", stdout, stderr);
+    let mut output = String::new();
+    let mut result = capture(
+        &mut stdout,
+        &job.settings.capture,
+        &mut output,
+        &mut transcript,
+    )
+    .await;
 
-    assert!(result.status.success(), "SSH run failed");
+    // capture reports the end of the output like a timeout, but here it means ssh exited.
+    let ended = matches!(
+        timeout(Duration::ZERO, stdout.fill_buf()).await,
+        Ok(Ok(rest)) if rest.is_empty()
+    );
+    if ended && let Err(Error::TimeoutWaitingForBenchmarkToEnd(_)) = result {
+        let status = child.wait().await?;
+        result = Err(Error::CommandFailed(if status.success() {
+            "lvglsim exited before the benchmark finished".to_string()
+        } else {
+            format!("lvglsim exited with {status} before the benchmark finished")
+        }));
+    } else {
+        // lvglsim may still run after a success marker, a crash or a timeout.
+        let _ = child.kill().await;
+        let _ = ssh(&target, "killall lvglsim").status().await;
+    }
 
-    std::fs::write(&results_p, format!("{}\n{}", stdout, stderr))?;
+    tokio::fs::write(&results_p, &output).await?;
+    job.record_output(&output);
+    result
+}
 
-    Ok(())
+pub async fn kill_rzg3e(job: &Job) -> Result<()> {
+    let target = ssh_target(job);
+    job.run_step("killall lvglsim", &mut ssh(&target, "killall lvglsim"))
+        .await
 }
 
-pub async fn kill_rzg3e(_: &BuilderSdk) -> Result<()> {
-    let result = Command::new("ssh")
-        .arg(format!("root@{RZG3E_ADDRESS}"))
-        .arg("killall lvglsim")
-        .spawn()?
-        .wait()
-        .await?;
-    assert!(result.success(), "Failed to kill process in Renesas RZ/G3E");
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn scp_arguments() {
+        let target = SshConfig {
+            identity_file: Some(PathBuf::from("/home/lvgl/.ssh/rzg3e")),
+            ..SshConfig::default()
+        };
+        let command = scp(&target, Path::new("build-wayland/bin/lvglsim"));
+        assert_eq!(command.as_std().get_program(), "scp");
+        assert_eq!(
+            args(&command),
+            vec![
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ConnectTimeout=5",
+                "-i",
+                "/home/lvgl/.ssh/rzg3e",
+                "-P",
+                "22",
+                "build-wayland/bin/lvglsim",
+                "root@192.168.1.172:./",
+            ]
+        );
+    }
+
+    #[test]
+    fn ssh_quotes_remote_dir() {
+        let target = SshConfig {
+            remote_dir: "lv bench's".to_string(),
+            ..SshConfig::default()
+        };
+        let command = ssh(
+            &target,
+            &format!("cd {} && ./lvglsim 2>&1", shell_quote(&target.remote_dir)),
+        );
+        assert_eq!(
+            args(&command).last().unwrap(),
+            r"cd 'lv bench'\''s' && ./lvglsim 2>&1"
+        );
+    }
+
+    #[test]
+    fn connection_errors() {
+        assert!(is_connection_error(
+            "ssh: connect to host 192.168.1.172 port 22: Connection timed out\r\n"
+        ));
+        assert!(is_connection_error(
+            "ssh: connect to host 192.168.1.172 port 22: No route to host\r\n"
+        ));
+        assert!(!is_connection_error(
+            "root@192.168.1.172: Permission denied (publickey,password).\r\n"
+        ));
+        assert!(!is_connection_error(
+            "Warning: Identity file /home/lvgl/.ssh/rzg3e not accessible: No such file or directory.\n"
+        ));
+    }
 }
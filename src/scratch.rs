@@ -0,0 +1,11 @@
+//! Scratch folders for tests that work on real files.
+
+use std::path::PathBuf;
+
+/// Empty folder `name` under the system's temporary folder, unique to this test process.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ejlv_builder-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
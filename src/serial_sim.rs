@@ -0,0 +1,157 @@
+//! Simulated board on a pseudo-terminal, to test the serial capture without hardware.
+//!
+//! The host side gets the slave end of a pty pair as a regular [`SerialStream`], while a
+//! task plays the board on the master end: printing output, pausing and waiting for commands.
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tokio_serial::SerialStream;
+
+enum Event {
+    Print(Vec<u8>),
+    Delay(Duration),
+    Expect(Vec<u8>),
+    HangUp,
+}
+
+/// Script of what the simulated board does, in order.
+#[derive(Default)]
+pub struct Board {
+    events: Vec<Event>,
+}
+
+impl Board {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Print `bytes` as is, which may be invalid UTF-8 or a partial line.
+    pub fn print(mut self, bytes: impl AsRef<[u8]>) -> Self {
+        self.events.push(Event::Print(bytes.as_ref().to_vec()));
+        self
+    }
+
+    pub fn delay(mut self, millis: u64) -> Self {
+        self.events
+            .push(Event::Delay(Duration::from_millis(millis)));
+        self
+    }
+
+    /// Wait until the host wrote `text`, like a shell waiting for a command.
+    pub fn expect(mut self, text: &str) -> Self {
+        self.events.push(Event::Expect(text.as_bytes().to_vec()));
+        self
+    }
+
+    /// Close the board's end of the pty, like a board unplugged mid-run.
+    pub fn hang_up(mut self) -> Self {
+        self.events.push(Event::HangUp);
+        self
+    }
+
+    /// Print what a transcript recorded by [`crate::capture::Transcript`] holds, keeping its
+    /// timing sped up `speed` times.
+    pub fn replay(mut self, transcript: &str, speed: f64) -> Self {
+        let mut elapsed = 0.0;
+        for line in transcript.lines().filter(|line| !line.starts_with('#')) {
+            let (timestamp, bytes) = line
+                .strip_prefix("[+")
+                .and_then(|line| line.split_once("] "))
+                .expect("transcript line starts with a timestamp");
+            let timestamp: f64 = timestamp.trim().parse().expect("valid timestamp");
+
+            if timestamp > elapsed {
+                self.events.push(Event::Delay(Duration::from_secs_f64(
+                    (timestamp - elapsed) / speed,
+                )));
+                elapsed = timestamp;
+            }
+            self.events.push(Event::Print(unescape(bytes)));
+        }
+        self
+    }
+
+    /// Start the board, returning the port the host reads and a handle resolving to every
+    /// byte the host wrote, once the host closed its port.
+    pub fn spawn(self) -> (SerialStream, JoinHandle<Vec<u8>>) {
+        let (mut board, host) = SerialStream::pair().expect("failed to open a pty pair");
+
+        let handle = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 256];
+
+            for event in self.events {
+                match event {
+                    Event::Print(bytes) => {
+                        board.write_all(&bytes).await.unwrap();
+                        board.flush().await.unwrap();
+                    }
+                    Event::Delay(delay) => sleep(delay).await,
+                    Event::Expect(text) => {
+                        let start = received.len();
+                        while !contains(&received[start..], &text) {
+                            let n = board.read(&mut buffer).await.unwrap();
+                            received.extend_from_slice(&buffer[..n]);
+                        }
+                    }
+                    Event::HangUp => return received,
+                }
+            }
+
+            // Stay connected and keep listening until the host is done.
+            while let Ok(n) = board.read(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..n]);
+            }
+            received
+        });
+
+        (host, handle)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Reverse the escaping of the transcript lines.
+fn unescape(line: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut encoded = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+            continue;
+        }
+
+        match chars.next().expect("escape sequence") {
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            '0' => bytes.push(0),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                bytes.push(u8::from_str_radix(&hex, 16).expect("valid \\x escape"));
+            }
+            'u' => {
+                let hex: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                let c = char::from_u32(u32::from_str_radix(&hex, 16).expect("valid \\u escape"))
+                    .expect("valid char");
+                let mut encoded = [0u8; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+            }
+            c => bytes.push(c as u8),
+        }
+    }
+
+    bytes
+}
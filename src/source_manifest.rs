@@ -0,0 +1,138 @@
+//! List of the source files a build was configured with, to tell when CMake has to glob again.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use async_recursion::async_recursion;
+
+use crate::prelude::*;
+
+/// Name of the manifest, written in the build folder after each successful build.
+pub const FILE_NAME: &str = "ejlv_lvgl_sources.txt";
+
+/// Extensions of the files that make CMake's globbed source lists stale when added or removed.
+const SOURCE_EXTENSIONS: &[&str] = &["c", "cpp", "h", "hpp", "S", "s", "cmake"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SourceManifest {
+    files: BTreeSet<String>,
+}
+
+impl SourceManifest {
+    /// List the source files under `root`, relative to it.
+    pub async fn scan(root: &Path) -> Result<Self> {
+        let mut files = BTreeSet::new();
+        scan_dir(root, root, &mut files).await?;
+        Ok(Self { files })
+    }
+
+    /// Read a manifest written by [`SourceManifest::write`], `None` if there's none.
+    pub async fn read(path: &Path) -> Option<Self> {
+        let contents = tokio::fs::read_to_string(path).await.ok()?;
+        Some(Self {
+            files: contents.lines().map(str::to_string).collect(),
+        })
+    }
+
+    pub async fn write(&self, path: &Path) -> Result<()> {
+        let mut contents = String::new();
+        for file in &self.files {
+            contents.push_str(file);
+            contents.push('\n');
+        }
+        tokio::fs::write(path, contents).await?;
+        Ok(())
+    }
+
+    /// Files added and removed since `previous`.
+    pub fn changes<'a>(&'a self, previous: &'a Self) -> (Vec<&'a str>, Vec<&'a str>) {
+        let added = self.files.difference(&previous.files);
+        let removed = previous.files.difference(&self.files);
+        (
+            added.map(String::as_str).collect(),
+            removed.map(String::as_str).collect(),
+        )
+    }
+}
+
+#[async_recursion]
+async fn scan_dir(root: &Path, dir: &Path, files: &mut BTreeSet<String>) -> Result<()> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            scan_dir(root, &path, files).await?;
+        } else if file_name == "CMakeLists.txt"
+            || path
+                .extension()
+                .is_some_and(|ext| SOURCE_EXTENSIONS.iter().any(|source| ext == *source))
+        {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            files.insert(relative.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::test_dir;
+
+    fn manifest(files: &[&str]) -> SourceManifest {
+        SourceManifest {
+            files: files.iter().map(|file| file.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn changes_lists_added_and_removed_files() {
+        let previous = manifest(&[
+            "src/core/lv_obj.c",
+            "src/lv_conf_internal.h",
+            "CMakeLists.txt",
+        ]);
+        let current = manifest(&[
+            "src/core/lv_obj.c",
+            "CMakeLists.txt",
+            "src/widgets/lv_arc.c",
+        ]);
+
+        assert_eq!(
+            current.changes(&previous),
+            (vec!["src/widgets/lv_arc.c"], vec!["src/lv_conf_internal.h"])
+        );
+        assert_eq!(current.changes(&current), (vec![], vec![]));
+    }
+
+    #[tokio::test]
+    async fn scan_and_read_back() {
+        let root = test_dir("source-manifest");
+        std::fs::create_dir_all(root.join("src/core")).unwrap();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        for file in [
+            "CMakeLists.txt",
+            "src/core/lv_obj.c",
+            "README.md",
+            ".git/HEAD.c",
+        ] {
+            std::fs::write(root.join(file), "").unwrap();
+        }
+
+        let scanned = SourceManifest::scan(&root).await.unwrap();
+        assert_eq!(scanned, manifest(&["CMakeLists.txt", "src/core/lv_obj.c"]));
+
+        let path = root.join(FILE_NAME);
+        scanned.write(&path).await.unwrap();
+        assert_eq!(SourceManifest::read(&path).await, Some(scanned));
+        assert_eq!(SourceManifest::read(&root.join("missing.txt")).await, None);
+    }
+}
@@ -1,10 +1,19 @@
-use ej_builder_sdk::BuilderSdk;
+use std::path::Path;
+use std::time::Duration;
+
 use tokio::process::Command;
-use tracing::info;
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt};
+use tracing::{info, warn};
 
-use crate::{board_folder, lvgl_folder, prelude::*, results_path};
+use crate::capture::session;
+use crate::device_lock::DeviceLock;
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::discovery;
+use crate::source_manifest::{self, SourceManifest};
+use crate::{board_folder, firmware_size, job::Job, lv_conf, lvgl_folder, prelude::*};
 
-pub async fn build_stm32(sdk: &BuilderSdk) -> Result<()> {
+pub async fn build_stm32(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
     let nprocs = num_cpus::get();
 
     let project_path = board_folder(&sdk.config_path(), "lv_port_stm32u5g9j-dk2");
@@ -16,46 +25,192 @@ pub async fn build_stm32(sdk: &BuilderSdk) -> Result<()> {
     let defaults_conf_path = project_path.join(format!("{}.defaults", sdk.board_config_name()));
     let target_lv_conf_h_path = project_path.join("Core").join("Inc").join("lv_conf.h");
 
-    let result = Command::new("python3")
-        .arg(gen_lv_conf_script_path)
-        .arg("--template")
-        .arg(conf_template_path)
-        .arg("--defaults")
-        .arg(defaults_conf_path)
-        .arg("--config")
-        .arg(target_lv_conf_h_path)
-        .spawn()?
-        .wait()
+    // The board's lv_conf overrides go to a copy of the defaults, the checkout stays as is.
+    let defaults_conf_path = if job.settings.lv_conf.is_empty() {
+        defaults_conf_path
+    } else {
+        let defaults = tokio::fs::read_to_string(&defaults_conf_path).await?;
+        let merged_path = job.results_artifact_path("lv_conf.defaults");
+        tokio::fs::write(
+            &merged_path,
+            lv_conf::apply_defaults(&defaults, &job.settings.lv_conf),
+        )
         .await?;
+        merged_path
+    };
 
-    assert!(result.success(), "Config generation failed");
+    // Generated next to the results and only copied over when it changed, so make doesn't
+    // rebuild everything including lv_conf.h because of its timestamp alone.
+    let generated_lv_conf_h_path = job.results_artifact_path("lv_conf.h");
+    job.run_step(
+        "generate lv_conf.h",
+        Command::new("python3")
+            .arg(gen_lv_conf_script_path)
+            .arg("--template")
+            .arg(&conf_template_path)
+            .arg("--defaults")
+            .arg(&defaults_conf_path)
+            .arg("--config")
+            .arg(&generated_lv_conf_h_path),
+    )
+    .await?;
 
-    let result = Command::new("make")
-        .arg("-C")
-        .arg(&project_path)
-        .arg("clean")
-        .spawn()?
-        .wait()
-        .await?;
+    let generated = tokio::fs::read(&generated_lv_conf_h_path).await?;
+    validate_lv_conf(job, &conf_template_path, &defaults_conf_path, &generated).await?;
+    let lv_conf_changed = tokio::fs::read(&target_lv_conf_h_path).await.ok() != Some(generated);
+    if lv_conf_changed {
+        tokio::fs::copy(&generated_lv_conf_h_path, &target_lv_conf_h_path).await?;
+    }
 
-    assert!(result.success());
+    let build_path = project_path.join("build");
+    let manifest_path = build_path.join(source_manifest::FILE_NAME);
+    let sources = SourceManifest::scan(&lvgl_folder(&sdk.config_path())).await?;
 
-    let result = Command::new("make")
-        .arg("-C")
-        .arg(&project_path)
-        .arg(format!("-j{}", nprocs))
-        .spawn()?
-        .wait()
-        .await?;
+    let clean_reason = if lv_conf_changed {
+        Some("lv_conf.h changed".to_string())
+    } else {
+        match SourceManifest::read(&manifest_path).await {
+            None => Some("there's no source manifest from a previous build".to_string()),
+            Some(previous) => {
+                let (added, removed) = sources.changes(&previous);
+                (!added.is_empty() || !removed.is_empty()).then(|| {
+                    format!(
+                        "LVGL source files changed, {} added: [{}], {} removed: [{}]",
+                        added.len(),
+                        added.join(", "),
+                        removed.len(),
+                        removed.join(", ")
+                    )
+                })
+            }
+        }
+    };
+
+    match clean_reason {
+        Some(reason) => {
+            info!("Clean build: {reason}");
+            job.run_step(
+                "make clean",
+                Command::new("make")
+                    .arg("-C")
+                    .arg(&project_path)
+                    .arg("clean"),
+            )
+            .await?;
+        }
+        None => info!("Incremental build: lv_conf.h and the LVGL source files are unchanged"),
+    }
 
-    assert!(result.success());
+    job.run_step(
+        "make",
+        Command::new("make")
+            .arg("-C")
+            .arg(&project_path)
+            .arg(format!("-j{}", nprocs)),
+    )
+    .await?;
+
+    // `make clean` removes the build folder, so the manifest is written after building.
+    sources.write(&manifest_path).await?;
+
+    match firmware_size::find_elf(&build_path) {
+        Some(elf) => firmware_size::report(job, &elf, Some(&elf.with_extension("map"))).await?,
+        None => warn!("No ELF file found in the STM32 build folder, skipping size report"),
+    }
 
     Ok(())
 }
 
-pub async fn run_stm32(sdk: &BuilderSdk) -> Result<()> {
-    info!("Benchmark runs on the stm32 are disabled for now.");
-    let results_p = results_path(&sdk.config_path(), "stm32u5g9");
-    std::fs::write(&results_p, "Skip")?;
+/// Warn about defaults that had no effect on the generated `lv_conf.h` and save the options
+/// that differ from the template next to the results.
+async fn validate_lv_conf(
+    job: &Job,
+    template_path: &Path,
+    defaults_path: &Path,
+    generated: &[u8],
+) -> Result<()> {
+    let template = tokio::fs::read_to_string(template_path).await?;
+    let defaults = tokio::fs::read_to_string(defaults_path).await?;
+    let validation = lv_conf::validate(&template, &defaults, &String::from_utf8_lossy(generated));
+
+    for problem in validation.problems {
+        warn!(
+            "{}:{}: {}",
+            defaults_path.display(),
+            problem.line,
+            problem.message
+        );
+        job.record_diagnostic(Diagnostic {
+            file: defaults_path.to_string_lossy().into_owned(),
+            line: problem.line,
+            column: None,
+            severity: Severity::Warning,
+            message: problem.message,
+        });
+    }
+
+    tokio::fs::write(job.results_artifact_path("lv_conf.diff"), validation.diff).await?;
     Ok(())
 }
+
+/// Flash the build with the configured tool, then capture the benchmark from `app_port`,
+/// usually the ST-LINK virtual COM port.
+pub async fn run_stm32(job: &Job) -> Result<()> {
+    let sdk = &job.sdk;
+    let project_path = board_folder(&sdk.config_path(), "lv_port_stm32u5g9j-dk2");
+    let results_p = job.results_path();
+    let _ = std::fs::remove_file(&results_p);
+
+    let Some(app_port) = &job.settings.app_port else {
+        return Err(Error::ConfigError(
+            "STM32 boards need an app_port to run, e.g. the ST-LINK virtual COM port".to_string(),
+        ));
+    };
+    let Some((program, args)) = job.settings.stm32.flash.split_first() else {
+        return Err(Error::ConfigError(
+            "STM32 boards need a flash command to run".to_string(),
+        ));
+    };
+    let Some(elf) = firmware_size::find_elf(&project_path.join("build")) else {
+        return Err(Error::FlashFailed(format!(
+            "No ELF file found in {}",
+            project_path.join("build").display()
+        )));
+    };
+
+    let _lock = DeviceLock::acquire(job, &discovery::device_id(app_port)?).await?;
+
+    // Opened before flashing, the board starts the benchmark as soon as it's reset.
+    let port_path = discovery::find_port(app_port)?
+        .ok_or_else(|| Error::DeviceNotFound(format!("STM32 {}", discovery::describe(app_port))))?;
+    let _port_lock = DeviceLock::acquire_port(job, &port_path).await?;
+    let mut port = tokio_serial::new(&port_path, 115_200)
+        .timeout(Duration::from_secs(job.settings.capture.idle_timeout_secs))
+        .open_native_async()?;
+
+    let placeholder = |arg: &String| {
+        arg.replace("{elf}", &elf.to_string_lossy())
+            .replace("{hex}", &elf.with_extension("hex").to_string_lossy())
+            .replace("{bin}", &elf.with_extension("bin").to_string_lossy())
+    };
+    // Drop what the previous firmware printed so far. The flash command resets the board when
+    // it's done, so clearing afterwards would drop the start of the new firmware's output.
+    port.clear(ClearBuffer::Input)?;
+    job.run_step(
+        "stm32 flash",
+        Command::new(placeholder(program)).args(args.iter().map(placeholder)),
+    )
+    .await?;
+
+    let mut output = String::new();
+    let result = session(
+        &mut port,
+        &job.settings,
+        &results_p,
+        &job.results_artifact_path("transcript.log"),
+        &mut output,
+    )
+    .await;
+    job.record_output(&output);
+    result
+}